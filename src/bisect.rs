@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use log::info;
+
+use crate::{change_dir, fetch_repo, recover_specs, run, try_build_tests, Config, Lock};
+
+/// Find the first commit between the locked commit of a repo and its upstream
+/// head where the tests fail to build.
+pub fn bisect(config: &Config, lock: &Lock, name: &str) -> Result<String> {
+    let repo = config
+        .find_repo(name)
+        .with_context(|| format!("unknown repo `{}`", name))?;
+    let good = lock
+        .find_commit(name)
+        .with_context(|| format!("no locked commit for `{}`", name))?;
+    if !Path::new(config.specs_dir()).exists() {
+        bail!("no specs directory, tests must be generated first");
+    }
+
+    let _cd = change_dir(config.specs_dir());
+    recover_specs()?;
+    let bad = fetch_repo(repo, lock)?;
+
+    // Check the endpoints first, otherwise the result is meaningless
+    run("git", &["checkout", "--detach", good])?;
+    if let Err(err) = try_build_tests(repo, false) {
+        bail!("locked commit {} fails to build: {:?}", good, err);
+    }
+    run("git", &["checkout", "--detach", &bad])?;
+    if try_build_tests(repo, false).is_ok() {
+        bail!("upstream {} builds successfully", bad);
+    }
+
+    // Let git pick the commits, using the build as the test
+    run("git", &["checkout", &repo.name])?;
+    let result = (|| {
+        let mut output = run("git", &["bisect", "start", &bad, good])?;
+        loop {
+            if let Some(line) = output
+                .lines()
+                .find(|x| x.ends_with("is the first bad commit"))
+            {
+                let hash = line.split_whitespace().next().unwrap();
+                return run("git", &["log", "--oneline", "-n", "1", hash]);
+            }
+
+            let verdict = if try_build_tests(repo, false).is_ok() {
+                "good"
+            } else {
+                "bad"
+            };
+            info!("{}", verdict);
+            output = run("git", &["bisect", verdict])?;
+        }
+    })();
+    run("git", &["bisect", "reset"])?;
+    result
+}
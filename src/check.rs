@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path};
+
+use regex::Regex;
+
+use crate::{features, layout, sort_repos, Config, Lock};
+
+/// Validate a config and lock without touching the network, returning every
+/// problem found.
+pub fn check(config: &Config, lock: &Lock) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // Repo names are used for remotes and branches, so must be unique
+    let mut names = HashSet::new();
+    for repo in &config.repos {
+        if !names.insert(repo.name.as_str()) {
+            problems.push(format!("{}: duplicate repo name", repo.name));
+        }
+    }
+
+    // Each repo is output into a directory of its own
+    let mut output_names = HashSet::new();
+    for repo in &config.repos {
+        let output_name = repo.output_name();
+        if output_name.is_empty() || output_name.contains('/') || output_name.starts_with('.') {
+            problems.push(format!(
+                "{}: invalid output name `{}`",
+                repo.name, output_name
+            ));
+        } else if !output_names.insert(output_name) {
+            problems.push(format!(
+                "{}: output name `{}` is used by another repo",
+                repo.name, output_name
+            ));
+        } else if config.dedup_dir.as_deref() == Some(output_name) {
+            problems.push(format!(
+                "{}: output name `{}` is the same as `dedup_dir`",
+                repo.name, output_name
+            ));
+        }
+    }
+
+    // Parents must be defined, and must not form a cycle
+    let mut unknown_parent = false;
+    for repo in &config.repos {
+        for parent in &repo.parent {
+            if config.find_repo(parent).is_none() {
+                problems.push(format!("{}: unknown parent `{}`", repo.name, parent));
+                unknown_parent = true;
+            }
+        }
+    }
+    if !unknown_parent {
+        if let Err(err) = sort_repos(config) {
+            problems.push(err.to_string());
+        }
+    }
+
+    // Variants are output into a directory named after them
+    for repo in &config.repos {
+        let mut variants = HashSet::new();
+        for variant in &repo.variants {
+            if !variants.insert(variant.name.as_str()) {
+                problems.push(format!(
+                    "{}: duplicate variant name `{}`",
+                    repo.name, variant.name
+                ));
+            }
+        }
+    }
+
+    // Extra directories are copied to the same path in the output, so must
+    // stay inside the repo
+    for repo in &config.repos {
+        for dir in &repo.extra_dirs {
+            let path = Path::new(dir);
+            if path.is_absolute() || path.components().any(|x| x == Component::ParentDir) {
+                problems.push(format!(
+                    "{}: extra directory `{}` must be relative to the repo",
+                    repo.name, dir
+                ));
+            }
+        }
+    }
+
+    // The build script is run from the worktree, and is what the interpreter
+    // is for
+    for repo in &config.repos {
+        match &repo.build_script {
+            Some(script) => {
+                let path = Path::new(script);
+                if path.is_absolute() || path.components().any(|x| x == Component::ParentDir) {
+                    problems.push(format!(
+                        "{}: build script `{}` must be relative to the repo",
+                        repo.name, script
+                    ));
+                }
+            }
+            None if repo.python.is_some() => {
+                problems.push(format!("{}: `python` requires `build_script`", repo.name));
+            }
+            None => {}
+        }
+    }
+
+    // The interpreter's outputs are restored into the worktree from the cache
+    for repo in &config.repos {
+        if repo.interpreter_build.is_none() && !repo.interpreter_outputs.is_empty() {
+            problems.push(format!(
+                "{}: `interpreter_outputs` requires `interpreter_build`",
+                repo.name
+            ));
+        }
+        for output in &repo.interpreter_outputs {
+            let path = Path::new(output);
+            if path.is_absolute() || path.components().any(|x| x == Component::ParentDir) {
+                problems.push(format!(
+                    "{}: interpreter output `{}` must be relative to the repo",
+                    repo.name, output
+                ));
+            }
+        }
+    }
+
+    // A repo can't be both shallow and have its full history
+    for repo in &config.repos {
+        match repo.fetch_depth {
+            Some(0) => problems.push(format!("{}: `fetch_depth` must be positive", repo.name)),
+            Some(_) if repo.full_history => problems.push(format!(
+                "{}: `fetch_depth` can't be combined with `full_history`",
+                repo.name
+            )),
+            _ => {}
+        }
+    }
+
+    // The integration branch must not clobber a repo's branch
+    if let Some(branch) = &config.integration_branch {
+        if config.find_repo(branch).is_some() {
+            problems.push(format!(
+                "integration branch `{}` has the same name as a repo",
+                branch
+            ));
+        }
+    }
+
+    // Pull requests are opened for the commit of the output directory
+    if config.pull_request.is_some() && !config.commit_output {
+        problems.push("`pull_request` requires `commit_output`".to_owned());
+    }
+
+    // The shared directory for duplicates must not clobber a repo's tests
+    if let Some(dedup_dir) = &config.dedup_dir {
+        if config.find_repo(dedup_dir).is_some() {
+            problems.push(format!(
+                "dedup directory `{}` has the same name as a repo",
+                dedup_dir
+            ));
+        }
+    }
+
+    // The layout must place every test somewhere inside the output directory,
+    // and the steps reading the tests back only know the default layout
+    if let Some(template) = config.layout.as_ref().filter(|x| *x != layout::WPT) {
+        let unknown = Regex::new(r"\{[^}]*\}")
+            .unwrap()
+            .find_iter(template)
+            .find(|x| !layout::PLACEHOLDERS.contains(&x.as_str()));
+        let path = Path::new(template);
+        if let Some(placeholder) = unknown {
+            problems.push(format!(
+                "layout `{}` has an unknown placeholder `{}`",
+                template,
+                placeholder.as_str()
+            ));
+        } else if layout::PLACEHOLDERS
+            .iter()
+            .any(|x| template.matches(x).count() > 1)
+            || !template.contains("{path}")
+        {
+            problems.push(format!(
+                "layout `{}` must have `{{path}}`, and each placeholder at most once",
+                template
+            ));
+        } else if path.is_absolute() || path.components().any(|x| x == Component::ParentDir) {
+            problems.push(format!(
+                "layout `{}` must be relative to the output directory",
+                template
+            ));
+        }
+    }
+    if config.any_js && config.layout() != layout::WPT {
+        problems.push("`any_js` requires the `wpt` layout".to_owned());
+    }
+    if config.layout() != layout::DEFAULT {
+        if config.rust_harness {
+            problems.push("`rust_harness` requires the default layout".to_owned());
+        }
+        if config.shell.is_some() {
+            problems.push("`shell` requires the default layout".to_owned());
+        }
+    }
+
+    // Features are filtered by name
+    let features = config.features.iter().flatten();
+    for name in features.chain(&config.excluded_features) {
+        if !features::is_feature(name) {
+            problems.push(format!("unknown feature `{}`", name));
+        }
+    }
+
+    // Every pattern must compile on its own
+    let mut check_patterns = |owner: &str, field: &str, patterns: &[String]| {
+        for pattern in patterns {
+            if let Err(err) = Regex::new(pattern) {
+                problems.push(format!(
+                    "{}: invalid pattern `{}` in {}: {}",
+                    owner, pattern, field, err
+                ));
+            }
+        }
+    };
+    check_patterns("config", "included_tests", &config.included_tests);
+    check_patterns("config", "excluded_tests", &config.excluded_tests);
+    check_patterns("config", "excluded_content", &config.excluded_content);
+    check_patterns("config", "ignored_paths", &config.ignored_paths);
+    let keys = |x: &BTreeMap<String, String>| x.keys().cloned().collect::<Vec<_>>();
+    check_patterns("config", "test_directives", &keys(&config.test_directives));
+    check_patterns("config", "long_timeout_tests", &config.long_timeout_tests);
+    for repo in &config.repos {
+        check_patterns(&repo.name, "included_tests", &repo.included_tests);
+        check_patterns(&repo.name, "excluded_tests", &repo.excluded_tests);
+        check_patterns(&repo.name, "excluded_content", &repo.excluded_content);
+        check_patterns(&repo.name, "test_directives", &keys(&repo.test_directives));
+        check_patterns(&repo.name, "long_timeout_tests", &repo.long_timeout_tests);
+    }
+
+    // Lock entries must be unique and refer to configured repos
+    let mut locked = HashSet::new();
+    for entry in &lock.repos {
+        if !locked.insert(entry.name.as_str()) {
+            problems.push(format!("{}: duplicate lock entry", entry.name));
+        }
+        if config.find_repo(&entry.name).is_none() {
+            problems.push(format!(
+                "{}: lock entry does not match any configured repo",
+                entry.name
+            ));
+        }
+    }
+
+    problems
+}
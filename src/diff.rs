@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::{find, layout, notify, Config, KINDS};
+
+#[derive(Debug, Default)]
+struct RepoDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+}
+
+/// The tests added, removed or modified for each repo between two output
+/// directories. Repos without changes are left out.
+#[derive(Debug, Default)]
+pub struct Changes {
+    repos: BTreeMap<String, RepoDiff>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.repos.is_empty()
+    }
+
+    /// A markdown list of the changes, with a heading of the given level for
+    /// each repo
+    pub fn to_markdown(&self, level: usize) -> String {
+        let mut report = String::new();
+        for (repo, repo_diff) in &self.repos {
+            // Layouts without the repo have every test under one heading
+            if !repo.is_empty() {
+                report.push_str(&format!("{} {}\n", "#".repeat(level), repo));
+            }
+            for (title, files) in &repo_diff.sections() {
+                if files.is_empty() {
+                    continue;
+                }
+                report.push_str(&format!("\n{}:\n", title));
+                for file in files.iter() {
+                    report.push_str(&format!("- {}\n", file));
+                }
+            }
+            report.push('\n');
+        }
+        report
+    }
+
+    /// The changes as a JSON list of repos
+    pub fn to_json(&self) -> String {
+        let list = |files: &[String]| {
+            let files: Vec<String> = files.iter().map(|x| notify::json_string(x)).collect();
+            format!("[{}]", files.join(","))
+        };
+        let repos: Vec<String> = self
+            .repos
+            .iter()
+            .map(|(repo, repo_diff)| {
+                format!(
+                    "{{\"name\":{},\"added\":{},\"removed\":{},\"modified\":{}}}",
+                    notify::json_string(repo),
+                    list(&repo_diff.added),
+                    list(&repo_diff.removed),
+                    list(&repo_diff.modified),
+                )
+            })
+            .collect();
+        format!("[{}]", repos.join(","))
+    }
+}
+
+impl RepoDiff {
+    fn sections(&self) -> [(&'static str, &Vec<String>); 3] {
+        [
+            ("Added", &self.added),
+            ("Removed", &self.removed),
+            ("Modified", &self.modified),
+        ]
+    }
+}
+
+/// Compare the tests of two output directories in the layout of the config,
+/// returning a markdown list of the tests added, removed or modified for each
+/// repo, if any.
+pub fn diff(old_dir: &Path, new_dir: &Path, config: &Config) -> Result<Option<String>> {
+    let changes = changes(old_dir, new_dir, config)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(changes.to_markdown(2)))
+}
+
+/// Compare the tests of two output directories in the layout of the config
+pub fn changes(old_dir: &Path, new_dir: &Path, config: &Config) -> Result<Changes> {
+    for dir in &[old_dir, new_dir] {
+        if !dir.is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+    }
+
+    let parser = layout::Parser::new(config)?;
+    let old_files = files(old_dir, &parser);
+    let new_files = files(new_dir, &parser);
+    let tests: BTreeSet<&(usize, String, String)> =
+        old_files.keys().chain(new_files.keys()).collect();
+    let mut repos: BTreeMap<String, RepoDiff> = BTreeMap::new();
+    for test @ (kind, repo, path) in tests {
+        let name = match KINDS.get(*kind) {
+            Some(kind) => format!("{}/{}", kind, path),
+            None => path.clone(),
+        };
+        let repo_diff = repos.entry(repo.clone()).or_default();
+        match (old_files.get(test), new_files.get(test)) {
+            (Some(old), Some(new)) => {
+                if fs::read(old_dir.join(old))? != fs::read(new_dir.join(new))? {
+                    repo_diff.modified.push(name);
+                }
+            }
+            (Some(_), None) => repo_diff.removed.push(name),
+            _ => repo_diff.added.push(name),
+        }
+    }
+
+    repos.retain(|_, x| x.sections().iter().any(|(_, files)| !files.is_empty()));
+    Ok(Changes { repos })
+}
+
+// Every file matching the layout, by the index of its kind in `KINDS` (or past
+// the end if the layout has no kind), its repo and its path, as the file's path
+// relative to the directory
+fn files(dir: &Path, parser: &layout::Parser) -> BTreeMap<(usize, String, String), PathBuf> {
+    let mut files = BTreeMap::new();
+    if !dir.exists() {
+        return files;
+    }
+    for path in find(dir.to_str().unwrap()) {
+        let relative = path.strip_prefix(dir).unwrap();
+        let (kind, repo, path) = match parser.parse(relative.to_str().unwrap()) {
+            Some(test) => test,
+            None => continue,
+        };
+        let kind = kind
+            .and_then(|kind| KINDS.iter().position(|x| *x == kind))
+            .unwrap_or(KINDS.len());
+        let test = (kind, repo, path);
+        files.insert(test, relative.to_path_buf());
+    }
+    files
+}
@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+/// An exclusive lock on a directory, released when dropped
+pub struct DirLock {
+    _file: File,
+}
+
+/// Lock a directory against other runs of this tool, waiting for them to
+/// release it if `wait`, otherwise failing straight away. The lock is advisory,
+/// so git commands run by hand aren't stopped.
+pub fn acquire(dir: &Path, wait: bool) -> Result<DirLock> {
+    let file = File::open(dir).with_context(|| format!("failed to open {}", dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::io;
+        use std::os::unix::io::AsRawFd;
+
+        let fd = file.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err).with_context(|| format!("failed to lock {}", dir.display()));
+            }
+            if !wait {
+                anyhow::bail!(
+                    "another run is using {}, pass --wait to wait for it to finish",
+                    dir.display()
+                );
+            }
+            eprintln!("Waiting for another run to finish using {}", dir.display());
+            while unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err).with_context(|| format!("failed to lock {}", dir.display()));
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = wait;
+    Ok(DirLock { _file: file })
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context as _, Result};
+use regex::Regex;
+
+use crate::{run, Config};
+
+const PROPOSALS_INDEX: &str =
+    "https://raw.githubusercontent.com/WebAssembly/proposals/main/README.md";
+
+// Repos linked from the proposals index that aren't proposals
+const NOT_PROPOSALS: &[&str] = &["design", "meetings", "proposals"];
+
+/// Find the proposal repos in the WebAssembly organization that aren't in the
+/// config, from the repos named like `*-proposal*` and the repos linked from
+/// the proposals index.
+pub fn discover(config: &Config) -> Result<Vec<String>> {
+    let mut names = BTreeSet::new();
+
+    let repos = run(
+        "gh",
+        &[
+            "api",
+            "--paginate",
+            "orgs/WebAssembly/repos",
+            "--jq",
+            ".[] | select(.archived | not) | .name",
+        ],
+    )
+    .context("failed to list the repos of the WebAssembly organization")?;
+    names.extend(
+        repos
+            .lines()
+            .filter(|x| x.contains("-proposal"))
+            .map(|x| x.to_owned()),
+    );
+
+    let index =
+        run("curl", &["-sSfL", PROPOSALS_INDEX]).context("failed to fetch the proposals index")?;
+    // Links are either inline in the tables, or reference definitions
+    let link = Regex::new(r"https://github\.com/WebAssembly/([\w.-]+)").unwrap();
+    for line in index
+        .lines()
+        .filter(|x| x.starts_with('|') || x.starts_with('['))
+    {
+        names.extend(
+            link.captures_iter(line)
+                .map(|x| x[1].trim_end_matches(".git").to_owned())
+                .filter(|x| !NOT_PROPOSALS.contains(&x.as_str())),
+        );
+    }
+
+    let tracked: BTreeSet<String> = config
+        .repos
+        .iter()
+        .filter_map(|x| github_repo(&x.url))
+        .collect();
+    Ok(names
+        .into_iter()
+        .filter(|x| !tracked.contains(&format!("webassembly/{}", x.to_lowercase())))
+        .collect())
+}
+
+// The lowercased `owner/name` of a GitHub URL
+fn github_repo(url: &str) -> Option<String> {
+    let regex = Regex::new(r"github\.com[:/]([^/]+)/([^/]+?)(\.git)?/?$").unwrap();
+    let captures = regex.captures(url)?;
+    Some(format!("{}/{}", &captures[1], &captures[2]).to_lowercase())
+}
+
+/// Config entries for the discovered repos, based on `spec` if it's tracked.
+pub fn scaffold(config: &Config, names: &[String]) -> String {
+    let mut text = String::new();
+    for name in names {
+        text.push_str(&format!(
+            "[[repos]]\nname = \"{}\"\nurl = \"https://github.com/WebAssembly/{}\"\n",
+            name, name
+        ));
+        if config.find_repo("spec").is_some() {
+            text.push_str("parent = \"spec\"\n");
+        }
+        text.push('\n');
+    }
+    text
+}
@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::info;
+
+use crate::{find, Config, DIRECTIVES_FILES, KINDS};
+
+// The fraction of lines two files must share to be considered near-identical
+const SIMILARITY_THRESHOLD: f64 = 0.9;
+
+pub struct Duplicate {
+    pub kind: String,
+    /// The duplicated file, relative to the output directory of each repo
+    pub path: String,
+    /// The output names of the repos with a copy
+    pub repos: Vec<String>,
+    /// The fraction of shared lines, or `None` if the files are identical
+    pub similarity: Option<f64>,
+}
+
+/// Find tests that are identical or near-identical across repos. Harness files
+/// and directives are ignored.
+pub fn find_duplicates(config: &Config, output_dir: &Path) -> Result<Vec<Duplicate>> {
+    let mut duplicates = Vec::new();
+    for kind in KINDS {
+        // Collect the contents of every copy of each test, in config order
+        let mut copies: BTreeMap<String, Vec<(&str, Vec<u8>)>> = BTreeMap::new();
+        for repo in &config.repos {
+            let dir = output_dir.join(kind).join(repo.output_name());
+            if !dir.exists() {
+                continue;
+            }
+            for path in find(dir.to_str().unwrap()) {
+                let relative = path.strip_prefix(&dir).unwrap();
+                if relative.starts_with("harness")
+                    || DIRECTIVES_FILES.iter().any(|x| relative == Path::new(x))
+                {
+                    continue;
+                }
+                copies
+                    .entry(relative.to_str().unwrap().to_owned())
+                    .or_default()
+                    .push((repo.output_name(), fs::read(&path)?));
+            }
+        }
+
+        for (path, copies) in copies {
+            // Group the identical copies together
+            let mut groups: Vec<(Vec<&str>, &[u8])> = Vec::new();
+            for (repo, contents) in &copies {
+                match groups.iter_mut().find(|(_, x)| x == contents) {
+                    Some((repos, _)) => repos.push(repo),
+                    None => groups.push((vec![repo], contents)),
+                }
+            }
+
+            for (repos, _) in &groups {
+                if repos.len() > 1 {
+                    duplicates.push(Duplicate {
+                        kind: kind.to_string(),
+                        path: path.clone(),
+                        repos: repos.iter().map(|x| x.to_string()).collect(),
+                        similarity: None,
+                    });
+                }
+            }
+
+            // Compare the distinct versions against each other
+            for (i, (a_repos, a)) in groups.iter().enumerate() {
+                for (b_repos, b) in &groups[i + 1..] {
+                    let similarity =
+                        similarity(&String::from_utf8_lossy(a), &String::from_utf8_lossy(b));
+                    if similarity >= SIMILARITY_THRESHOLD {
+                        duplicates.push(Duplicate {
+                            kind: kind.to_string(),
+                            path: path.clone(),
+                            repos: vec![a_repos[0].to_owned(), b_repos[0].to_owned()],
+                            similarity: Some(similarity),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+// The fraction of lines shared between two files
+fn similarity(a: &str, b: &str) -> f64 {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for line in a.lines() {
+        *counts.entry(line).or_insert(0) += 1;
+    }
+    let mut shared = 0;
+    for line in b.lines() {
+        if let Some(count) = counts.get_mut(line) {
+            if *count > 0 {
+                *count -= 1;
+                shared += 1;
+            }
+        }
+    }
+
+    let total = a.lines().count().max(b.lines().count());
+    if total == 0 {
+        1.0
+    } else {
+        shared as f64 / total as f64
+    }
+}
+
+/// Move tests that are identical across repos into a shared directory for each
+/// kind, along with the harness of the first repo if the kind has one. A test
+/// is only moved if every other version of it is unique to its repo, and the
+/// repos share the same harness, so the shared copy stands for every repo it
+/// was moved from. Returns the duplicates that were moved.
+pub fn deduplicate<'a>(
+    output_dir: &Path,
+    duplicates: &'a [Duplicate],
+    shared: &str,
+) -> Result<Vec<&'a Duplicate>> {
+    let identical: Vec<&Duplicate> = duplicates
+        .iter()
+        .filter(|x| x.similarity.is_none())
+        .collect();
+    let mut moved = Vec::new();
+    for duplicate in &identical {
+        let versions = identical
+            .iter()
+            .filter(|x| x.kind == duplicate.kind && x.path == duplicate.path)
+            .count();
+        if versions > 1 {
+            info!(
+                "Keeping {}/{} in each repo, as {} versions of it are shared",
+                duplicate.kind, duplicate.path, versions
+            );
+            continue;
+        }
+
+        let kind_dir = output_dir.join(&duplicate.kind);
+        let harness_dir = kind_dir.join(&duplicate.repos[0]).join("harness");
+        let shared_harness_dir = kind_dir.join(shared).join("harness");
+        let first = harness(&harness_dir)?;
+        let mut others = Vec::new();
+        for repo in &duplicate.repos[1..] {
+            others.push(harness(&kind_dir.join(repo).join("harness"))?);
+        }
+        if shared_harness_dir.exists() {
+            others.push(harness(&shared_harness_dir)?);
+        }
+        if others.iter().any(|x| *x != first) {
+            info!(
+                "Keeping {}/{} in each repo, as their harnesses differ",
+                duplicate.kind, duplicate.path
+            );
+            continue;
+        }
+
+        let shared_path = kind_dir.join(shared).join(&duplicate.path);
+        fs::create_dir_all(shared_path.parent().unwrap())?;
+        fs::copy(
+            kind_dir.join(&duplicate.repos[0]).join(&duplicate.path),
+            &shared_path,
+        )?;
+        for repo in &duplicate.repos {
+            fs::remove_file(kind_dir.join(repo).join(&duplicate.path))?;
+        }
+        if !first.is_empty() && !shared_harness_dir.exists() {
+            for (path, contents) in &first {
+                let shared_path = shared_harness_dir.join(path);
+                fs::create_dir_all(shared_path.parent().unwrap())?;
+                fs::write(&shared_path, contents)?;
+            }
+        }
+        moved.push(*duplicate);
+    }
+    Ok(moved)
+}
+
+// The contents of every file of a harness directory, by path, or nothing if
+// there is no harness
+fn harness(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    if dir.exists() {
+        for path in find(dir.to_str().unwrap()) {
+            let contents = fs::read(&path)?;
+            files.insert(path.strip_prefix(dir).unwrap().to_path_buf(), contents);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use wast::parser::{self, ParseBuffer};
+use wast::{
+    DataKind, ElemKind, Expression, GlobalKind, Instruction, ItemKind, MemoryKind, MemoryType,
+    ModuleField, ModuleKind, QuoteModule, Wast, WastDirective,
+};
+
+use crate::manifest::Manifest;
+use crate::{remove_empty_dirs, run, write_string};
+
+// The text format that needs each feature, named like the proposal repos. These
+// are matched against a script with its comments and strings removed, and its
+// identifiers replaced by `$`. The features of memories and constant
+// expressions are also read from the parsed modules, where the script parses.
+const FEATURES: &[(&str, &str)] = &[
+    (
+        "exception-handling",
+        r"\b(try|try_table|catch|catch_all|catch_ref|catch_all_ref|delegate|throw|throw_ref|rethrow|exnref|assert_exception)\b|\(tag\b",
+    ),
+    (
+        "function-references",
+        r"\b(call_ref|return_call_ref|ref\.as_non_null|br_on_null|br_on_non_null)\b|\(ref\s+(null\s+)?[\d$]",
+    ),
+    (
+        "extended-const",
+        r"\((global(\s+\$)?(\s+\(export\s+\))*\s+(\(mut\s+\w+\)|\w+)|offset)\s+\((i32|i64)\.(add|sub|mul)\b",
+    ),
+    (
+        "gc",
+        r"\b(struct|array)\.\w+|\b(ref\.(i31|test|cast|eq)|i31\.get_[su]|br_on_cast|br_on_cast_fail|any\.convert_extern|extern\.convert_any)\b|\((rec|sub|struct|array)\b|\b(anyref|eqref|i31ref|structref|arrayref|nullref|nullfuncref|nullexternref)\b",
+    ),
+    (
+        "memory64",
+        r"\((memory|table)(\s+\$)?(\s+\(import\s+\)|\s+\(export\s+\))*\s+i64\b",
+    ),
+    (
+        "multi-memory",
+        r"\b(i32|i64|f32|f64)\.(load|store)\w*\s+[\d$]|\bmemory\.(size|grow|fill|copy)\s+[\d$]",
+    ),
+    ("relaxed-simd", r"\.relaxed_\w+"),
+    (
+        "simd",
+        r"\bv128\b|\b(i8x16|i16x8|i32x4|i64x2|f32x4|f64x2)\.\w+",
+    ),
+    (
+        "tail-call",
+        r"\b(return_call|return_call_indirect|return_call_ref)\b",
+    ),
+    ("threads", r"\bshared\b|\.atomic\.\w+|\batomic\.fence\b"),
+];
+
+/// Tag every `.wast` script's outputs, whether the script is copied or
+/// converted, with the features it uses, writing them to `FEATURES.toml` in the
+/// output directory. Must be run from the specs directory.
+pub fn write_features(output_dir: &Path, manifest: &Manifest) -> Result<()> {
+    let mut tags: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (tests, features) in tag_tests(output_dir, manifest)? {
+        for test in tests {
+            tags.insert(test.to_str().unwrap().to_owned(), features.clone());
+        }
+    }
+
+    write_string(
+        output_dir.join("FEATURES.toml"),
+        &toml::to_string_pretty(&tags)?,
+    )
+}
+
+/// Remove every output of the `.wast` scripts that use a feature not in
+/// `features`, if given, or in `excluded`. Returns the removed tests. Must be
+/// run from the specs directory.
+pub fn filter(
+    output_dir: &Path,
+    manifest: &Manifest,
+    features: Option<&[String]>,
+    excluded: &[String],
+) -> Result<Vec<String>> {
+    for name in features.into_iter().flatten().chain(excluded) {
+        if !is_feature(name) {
+            bail!("unknown feature `{}`", name);
+        }
+    }
+
+    let supported = |feature: &&str| {
+        features.is_none_or(|x| x.iter().any(|x| x == feature))
+            && !excluded.iter().any(|x| x == feature)
+    };
+    let mut removed = Vec::new();
+    for (tests, used) in tag_tests(output_dir, manifest)? {
+        if used.iter().all(supported) {
+            continue;
+        }
+        for test in tests {
+            fs::remove_file(output_dir.join(&test))?;
+            removed.push(test.to_str().unwrap().to_owned());
+        }
+    }
+    remove_empty_dirs(output_dir)?;
+    Ok(removed)
+}
+
+/// Whether there is a feature with this name
+pub fn is_feature(name: &str) -> bool {
+    FEATURES.iter().any(|(x, _)| *x == name)
+}
+
+// The outputs of every `.wast` script, relative to the output directory, with
+// the features the script uses. The outputs are found from the manifest, so
+// that the converted tests are tagged whether or not the script is copied.
+fn tag_tests(
+    output_dir: &Path,
+    manifest: &Manifest,
+) -> Result<Vec<(Vec<PathBuf>, Vec<&'static str>)>> {
+    let mut scripts: BTreeMap<(&str, &str), Vec<PathBuf>> = BTreeMap::new();
+    for file in manifest.files() {
+        let source = match &file.source {
+            Some(source) if source.ends_with(".wast") => source,
+            _ => continue,
+        };
+        if output_dir.join(&file.path).exists() {
+            scripts
+                .entry((&file.commit, source))
+                .or_default()
+                .push(PathBuf::from(&file.path));
+        }
+    }
+
+    let patterns = patterns();
+    let mut tagged = Vec::new();
+    for ((commit, source), tests) in scripts {
+        let script = run("git", &["show", &format!("{}:{}", commit, source)])?;
+        tagged.push((tests, detect(&script, &patterns)));
+    }
+    Ok(tagged)
+}
+
+fn patterns() -> Vec<(&'static str, Regex)> {
+    FEATURES
+        .iter()
+        .map(|(name, pattern)| (*name, Regex::new(pattern).unwrap()))
+        .collect()
+}
+
+// The features a script uses
+fn detect(script: &str, patterns: &[(&'static str, Regex)]) -> Vec<&'static str> {
+    let parsed = module_features(script);
+
+    // Names and comments could mention anything, and strings are either names
+    // or binary modules, which are skipped
+    let noise = Regex::new(r#"(?s)\(;.*?;\)|;;[^\n]*|"(?:\\.|[^"\\])*"|\$[^\s()"]+"#).unwrap();
+    let script = noise.replace_all(
+        script,
+        |x: &regex::Captures| {
+            if x[0].starts_with('$') {
+                "$"
+            } else {
+                " "
+            }
+        },
+    );
+    patterns
+        .iter()
+        .filter(|(name, pattern)| parsed.contains(name) || pattern.is_match(&script))
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+// The features the memories and constant expressions of a script's modules
+// use, or none if the script doesn't parse, e.g. for syntax newer than the
+// parser
+fn module_features(script: &str) -> Vec<&'static str> {
+    let mut features = Vec::new();
+    let buf = match ParseBuffer::new(script) {
+        Ok(buf) => buf,
+        Err(_) => return features,
+    };
+    let wast = match parser::parse::<Wast>(&buf) {
+        Ok(wast) => wast,
+        Err(_) => return features,
+    };
+
+    let extended = |expr: &Expression| {
+        expr.instrs.iter().any(|x| {
+            matches!(
+                x,
+                Instruction::I32Add
+                    | Instruction::I32Sub
+                    | Instruction::I32Mul
+                    | Instruction::I64Add
+                    | Instruction::I64Sub
+                    | Instruction::I64Mul
+            )
+        })
+    };
+    for directive in &wast.directives {
+        let module = match directive {
+            WastDirective::Module(module)
+            | WastDirective::AssertInvalid { module, .. }
+            | WastDirective::AssertUnlinkable { module, .. }
+            | WastDirective::AssertMalformed {
+                module: QuoteModule::Module(module),
+                ..
+            } => module,
+            _ => continue,
+        };
+        let fields = match &module.kind {
+            ModuleKind::Text(fields) => fields,
+            ModuleKind::Binary(_) => continue,
+        };
+
+        let mut memories = Vec::new();
+        for field in fields {
+            match field {
+                ModuleField::Memory(memory) => memories.push(match &memory.kind {
+                    MemoryKind::Import { ty, .. } | MemoryKind::Normal(ty) => {
+                        matches!(ty, MemoryType::B64 { .. })
+                    }
+                    MemoryKind::Inline { is_32, .. } => !is_32,
+                }),
+                ModuleField::Import(import) => {
+                    if let ItemKind::Memory(ty) = &import.item.kind {
+                        memories.push(matches!(ty, MemoryType::B64 { .. }));
+                    }
+                }
+                ModuleField::Global(global) => {
+                    if let GlobalKind::Inline(expr) = &global.kind {
+                        if extended(expr) {
+                            features.push("extended-const");
+                        }
+                    }
+                }
+                ModuleField::Elem(elem) => {
+                    if let ElemKind::Active { offset, .. } = &elem.kind {
+                        if extended(offset) {
+                            features.push("extended-const");
+                        }
+                    }
+                }
+                ModuleField::Data(data) => {
+                    if let DataKind::Active { offset, .. } = &data.kind {
+                        if extended(offset) {
+                            features.push("extended-const");
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if memories.iter().any(|is_64| *is_64) {
+            features.push("memory64");
+        }
+        if memories.len() > 1 {
+            features.push("multi-memory");
+        }
+    }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(script: &str) -> Vec<&'static str> {
+        detect(script, &patterns())
+    }
+
+    #[test]
+    fn memory64_with_ids() {
+        assert_eq!(features("(module (memory i64 1))"), ["memory64"]);
+        assert_eq!(features("(module (memory $m i64 1))"), ["memory64"]);
+        assert_eq!(
+            features("(module (memory $m (export \"m\") i64 1))"),
+            ["memory64"]
+        );
+        assert_eq!(
+            features("(module (import \"m\" \"m\" (memory $m i64 1)))"),
+            ["memory64"]
+        );
+        assert!(features("(module (memory $i64 1))").is_empty());
+    }
+
+    #[test]
+    fn multi_memory() {
+        let script = "(module (memory $a 1) (memory $b 1)\n\
+                      (func (result i32) (i32.load $b (i32.const 0))))";
+        assert_eq!(features(script), ["multi-memory"]);
+        let script = "(module (import \"m\" \"m\" (memory 1)) (memory 1))";
+        assert_eq!(features(script), ["multi-memory"]);
+        // Each module with a memory of its own is fine
+        assert!(features("(module (memory 1))\n(module $m (memory 1))").is_empty());
+    }
+
+    #[test]
+    fn extended_const() {
+        let script = "(module (global $g i32 (i32.add (i32.const 1) (i32.const 2))))";
+        assert_eq!(features(script), ["extended-const"]);
+        let script = "(module (memory 1) (data (offset (i32.mul (i32.const 1) (i32.const 2)))))";
+        assert_eq!(features(script), ["extended-const"]);
+        let script = "(module (global $g (mut i32) (i32.const 1))\n\
+                      (func (result i32) (i32.add (global.get $g) (i32.const 1))))";
+        assert!(features(script).is_empty());
+    }
+
+    #[test]
+    fn unparsed_scripts_use_patterns() {
+        // Newer syntax than the parser knows still has its features detected
+        let script = "(module (memory $m i64 1) (func try_table end))";
+        assert_eq!(features(script), ["exception-handling", "memory64"]);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{bail, Result};
+
+use crate::{path_contains, run, run_status, try_merge, Repo};
+
+#[derive(Debug, Default)]
+pub struct Integration {
+    /// Pairs of repos that conflict when merged, with the conflicting files
+    pub conflicts: Vec<(String, String, Vec<String>)>,
+    /// Repos that couldn't be merged into the integration branch
+    pub unmerged: Vec<String>,
+}
+
+/// Check every pair of repos for conflicts, then merge every repo in order
+/// into a single integration branch.
+pub fn build_integration(branch: &str, repos: &[&Repo]) -> Result<Integration> {
+    let mut integration = Integration::default();
+
+    for (i, a) in repos.iter().enumerate() {
+        for b in &repos[i + 1..] {
+            // Ignore conflicts that either repo would resolve automatically
+            let auto_resolve: Vec<&str> = a
+                .auto_resolve()
+                .into_iter()
+                .chain(b.auto_resolve())
+                .map(|(path, _)| path)
+                .collect();
+            let files: Vec<String> = conflicting_files(&a.name, &b.name)?
+                .into_iter()
+                .filter(|file| !auto_resolve.iter().any(|path| path_contains(path, file)))
+                .collect();
+            if !files.is_empty() {
+                integration
+                    .conflicts
+                    .push((a.name.clone(), b.name.clone(), files));
+            }
+        }
+    }
+
+    let (first, rest) = match repos.split_first() {
+        Some(x) => x,
+        None => return Ok(integration),
+    };
+    run("git", &["checkout", "-B", branch, &first.name])?;
+    for repo in rest {
+        let message = format!("Merging {} into {}", repo.name, branch);
+        if try_merge(&repo.name, &message, &repo.auto_resolve())?.is_some() {
+            integration.unmerged.push(repo.name.clone());
+        }
+    }
+
+    Ok(integration)
+}
+
+// Find the files that conflict when merging two branches, without touching the
+// working tree.
+fn conflicting_files(a: &str, b: &str) -> Result<Vec<String>> {
+    let output = run_status(
+        "git",
+        &[
+            "merge-tree",
+            "--write-tree",
+            "--name-only",
+            "--no-messages",
+            a,
+            b,
+        ],
+    )?;
+
+    // The first line is the resulting tree, followed by the conflicted files
+    match output.code {
+        Some(0) => Ok(Vec::new()),
+        Some(1) => Ok(output
+            .stdout
+            .lines()
+            .skip(1)
+            .take_while(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect()),
+        _ => bail!("git merge-tree {} {}: {}", a, b, output.stderr),
+    }
+}
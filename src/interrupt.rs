@@ -0,0 +1,39 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catch Ctrl-C and termination requests, so that the work in progress can be
+/// stopped at the next safe point instead of wherever the signal arrives.
+/// Commands being run still receive the signal, and fail.
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Whether a signal was caught since `install`
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// The error of a run stopped by a signal, describing what was left undone, so
+/// that it can exit as if killed by the signal
+#[derive(Debug)]
+pub struct Interrupted(pub String);
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interrupted, {}", self.0)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(unix)]
+extern "C" fn handle(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
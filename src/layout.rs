@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use regex::Regex;
+
+use crate::{find, remove_empty_dirs, write_string, Config, DIRECTIVES_FILES, KINDS};
+
+/// The layout of the output directory unless configured
+pub const DEFAULT: &str = "{kind}/{repo}/{path}";
+
+/// The layout mirroring web-platform-tests, so `wasm/` can be synced into a
+/// wpt checkout. The `.js` tests go in `wasm/core/${repo}` with their harness
+/// in `wasm/resources/${repo}`, and the JS API tests in `wasm/jsapi/${repo}`.
+/// The other kinds keep the default layout, outside of `wasm/`.
+pub const WPT: &str = "wpt";
+
+/// The placeholders a layout template may use
+pub const PLACEHOLDERS: &[&str] = &["{kind}", "{repo}", "{path}"];
+
+/// The path of an output file in a layout, from its kind, the output name of
+/// its repo, and its path within the repo's directory for the kind.
+pub fn arranged(layout: &str, kind: &str, repo: &str, path: &str) -> String {
+    let template = if layout == WPT {
+        match (kind, path.strip_prefix("harness/")) {
+            ("js", Some(harness)) => return format!("wasm/resources/{}/{}", repo, harness),
+            ("js", None) => "wasm/core/{repo}/{path}",
+            ("js-api", _) => "wasm/jsapi/{repo}/{path}",
+            _ => DEFAULT,
+        }
+    } else {
+        layout
+    };
+    template
+        .replace("{kind}", kind)
+        .replace("{repo}", repo)
+        .replace("{path}", path)
+}
+
+// The names of the variants of every repo, whose tests are output below a
+// directory of the same name
+fn variants(config: &Config) -> BTreeSet<&str> {
+    config
+        .repos
+        .iter()
+        .flat_map(|x| &x.variants)
+        .map(|x| x.name.as_str())
+        .collect()
+}
+
+/// Recovers the kind, repo and path of the files in the layout of a config
+pub struct Parser {
+    pattern: Regex,
+    wpt: bool,
+    variants: BTreeSet<String>,
+}
+
+impl Parser {
+    pub fn new(config: &Config) -> Result<Parser> {
+        let layout = config.layout();
+        let wpt = layout == WPT;
+        let template = if wpt { DEFAULT } else { layout };
+        let kinds: Vec<String> = KINDS.iter().map(|x| regex::escape(x)).collect();
+        let pattern = regex::escape(template)
+            .replace(r"\{kind\}", &format!("(?P<kind>{})", kinds.join("|")))
+            .replace(r"\{repo\}", "(?P<repo>[^/]+)")
+            .replace(r"\{path\}", "(?P<path>.+)");
+        Ok(Parser {
+            pattern: Regex::new(&format!("^{}$", pattern))?,
+            wpt,
+            variants: variants(config).into_iter().map(|x| x.to_owned()).collect(),
+        })
+    }
+
+    /// The kind, if the layout has one, repo and path of a file, relative to
+    /// the output directory. The repo of a variant's file is prefixed with
+    /// `${variant}/`. Returns `None` for files that aren't tests, including
+    /// the README, LICENSE and directives written for each repo.
+    pub fn parse(&self, path: &str) -> Option<(Option<&'static str>, String, String)> {
+        let (kind, repo, path) = match path.split_once('/') {
+            Some((variant, rest)) if self.variants.contains(variant) => {
+                let (kind, repo, path) = self.parse_unprefixed(rest)?;
+                let repo = if repo.is_empty() {
+                    variant.to_owned()
+                } else {
+                    format!("{}/{}", variant, repo)
+                };
+                (kind, repo, path)
+            }
+            _ => self.parse_unprefixed(path)?,
+        };
+        if path == "README.md" || path == "LICENSE" || DIRECTIVES_FILES.contains(&path.as_str()) {
+            return None;
+        }
+        Some((kind, repo, path))
+    }
+
+    fn parse_unprefixed(&self, path: &str) -> Option<(Option<&'static str>, String, String)> {
+        if self.wpt {
+            let split = |rest: &str| {
+                let (repo, path) = rest.split_once('/')?;
+                Some((repo.to_owned(), path.to_owned()))
+            };
+            if let Some(rest) = path.strip_prefix("wasm/core/") {
+                return split(rest).map(|(repo, path)| (Some("js"), repo, path));
+            }
+            if let Some(rest) = path.strip_prefix("wasm/resources/") {
+                return split(rest)
+                    .map(|(repo, path)| (Some("js"), repo, format!("harness/{}", path)));
+            }
+            if let Some(rest) = path.strip_prefix("wasm/jsapi/") {
+                return split(rest).map(|(repo, path)| (Some("js-api"), repo, path));
+            }
+        }
+
+        let captures = self.pattern.captures(path)?;
+        let kind = captures
+            .name("kind")
+            .and_then(|x| KINDS.iter().find(|kind| **kind == x.as_str()))
+            .copied();
+        let repo = captures.name("repo").map_or("", |x| x.as_str());
+        Some((kind, repo.to_owned(), captures["path"].to_owned()))
+    }
+}
+
+/// Move every file from `${kind}/${repo}/${path}`, or the same below a variant
+/// directory, to where the layout puts it. Files outside of these,
+/// such as `MANIFEST.toml`, stay where they are. Returns the new path of every
+/// moved file, relative to the output directory.
+pub fn arrange(
+    config: &Config,
+    template: &str,
+    output_dir: &Path,
+) -> Result<BTreeMap<String, String>> {
+    let variants = variants(config);
+
+    let mut moved = BTreeMap::new();
+    let mut destinations: BTreeMap<String, String> = BTreeMap::new();
+    for path in find(output_dir.to_str().unwrap()) {
+        let relative = path.strip_prefix(output_dir)?.to_str().unwrap().to_owned();
+        let components: Vec<&str> = relative.splitn(4, '/').collect();
+        let destination = match components.as_slice() {
+            [kind, repo, rest @ ..] if KINDS.contains(kind) && !rest.is_empty() => {
+                arranged(template, kind, repo, &rest.join("/"))
+            }
+            [variant, kind, repo, rest] if variants.contains(variant) && KINDS.contains(kind) => {
+                format!("{}/{}", variant, arranged(template, kind, repo, rest))
+            }
+            _ => relative.clone(),
+        };
+        if let Some(earlier) = destinations.insert(destination.clone(), relative.clone()) {
+            bail!(
+                "{} and {} are both arranged into {}",
+                earlier,
+                relative,
+                destination
+            );
+        }
+        if destination != relative {
+            moved.insert(relative, destination);
+        }
+    }
+
+    // Move through a scratch directory, so no file is overwritten before it's
+    // moved itself
+    let scratch = output_dir.join(".layout");
+    for (from, to) in &moved {
+        let to = scratch.join(to);
+        fs::create_dir_all(to.parent().unwrap())?;
+        fs::rename(output_dir.join(from), to)?;
+    }
+    for to in moved.values() {
+        let path = output_dir.join(to);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::rename(scratch.join(to), path)?;
+    }
+    remove_empty_dirs(output_dir)?;
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+
+    // Point the feature tags at the new paths
+    let features_path = output_dir.join("FEATURES.toml");
+    if features_path.exists() {
+        let tags: BTreeMap<String, Vec<String>> =
+            toml::from_str(&fs::read_to_string(&features_path)?)?;
+        let tags: BTreeMap<&str, Vec<String>> = tags
+            .iter()
+            .map(|(path, features)| {
+                let path = moved.get(path).unwrap_or(path);
+                (path.as_str(), features.clone())
+            })
+            .collect();
+        write_string(&features_path, &toml::to_string_pretty(&tags)?)?;
+    }
+    Ok(moved)
+}
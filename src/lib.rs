@@ -0,0 +1,4787 @@
+//! Generates the WebAssembly spec tests of the spec and proposal repositories
+//! in `config.toml`, merging each proposal with its parents.
+//!
+//! The `wasm-generate-testsuite` binary is a thin CLI over the commands in this
+//! crate. To embed test generation in another tool, use a [`Builder`] to build
+//! the tests of individual repos:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use wasm_generate_testsuite::{Builder, Config, Lock};
+//!
+//! let config = Config::load()?;
+//! let lock = Lock::load()?;
+//! let mut builder = Builder::new(config).with_lock(lock);
+//! for name in builder.repos()? {
+//!     let status = builder.build_repo(&name, Path::new("tests"))?;
+//!     println!("{}: {} {}", name, status.commit(), status.merged());
+//!     builder.update_lock(&name, &status);
+//! }
+//! builder.lock().write()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Paths are relative to the current directory, which is where `config.toml`
+//! and `config-lock.toml` are read from.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write as _;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context as _, Result};
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use serde::Deserializer;
+use serde_derive::{Deserialize, Serialize};
+use toml;
+use wast2js;
+
+use log::{debug, info, warn};
+
+mod bisect;
+mod check;
+mod diff;
+mod dir_lock;
+mod discover;
+mod duplicates;
+mod features;
+mod integration;
+mod interrupt;
+mod layout;
+mod list;
+mod manifest;
+mod notify;
+mod omit;
+mod prune;
+mod results;
+pub mod runner;
+mod rust_harness;
+mod upstream;
+mod which;
+
+pub use interrupt::Interrupted;
+
+// Data structures
+
+/// The options of a run from the command line, overriding the config
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Retry only the repos the previous run didn't complete
+    pub resume: bool,
+    /// Wait for another run using the specs directory to finish, rather than
+    /// failing
+    pub wait: bool,
+    /// Stash local changes in the specs directory, rather than failing
+    pub discard_local_changes: bool,
+    pub link_mode: Option<LinkMode>,
+    pub features: Option<Vec<String>>,
+    pub excluded_features: Option<Vec<String>>,
+    /// The kinds of tests to output for every repo, overriding what each skips
+    pub only: Option<Vec<String>>,
+    pub net_jobs: Option<NonZeroUsize>,
+    pub archive: Option<String>,
+}
+
+/// The configuration of the repos to generate tests for, from `config.toml`
+/// and `config.d/`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    // The directory containing the config, which relative paths are resolved
+    // against
+    #[serde(skip)]
+    root: PathBuf,
+    #[serde(default)]
+    specs_dir: Option<String>,
+    #[serde(default)]
+    output_dir: Option<String>,
+    #[serde(default)]
+    reports_dir: Option<String>,
+    #[serde(default)]
+    layout: Option<String>,
+    #[serde(default)]
+    any_js: bool,
+    #[serde(default)]
+    integration_branch: Option<String>,
+    #[serde(default)]
+    gc_interval_days: Option<u64>,
+    #[serde(default)]
+    post_process: Option<String>,
+    #[serde(default)]
+    diff_generated: bool,
+    #[serde(default)]
+    omit_identical: bool,
+    #[serde(default)]
+    report_duplicates: bool,
+    #[serde(default)]
+    dedup_dir: Option<String>,
+    #[serde(default)]
+    validate_wast: bool,
+    #[serde(default)]
+    exclude_invalid_wast: bool,
+    #[serde(default)]
+    check_js: Option<String>,
+    #[serde(default)]
+    wast2json: Option<String>,
+    #[serde(default)]
+    rust_harness: bool,
+    #[serde(default)]
+    feature_tags: bool,
+    #[serde(default)]
+    features: Option<Vec<String>>,
+    #[serde(default)]
+    excluded_features: Vec<String>,
+    #[serde(default)]
+    shell: Option<String>,
+    #[serde(default)]
+    shell_timeout: Option<u64>,
+    #[serde(default)]
+    expectations: Option<String>,
+    #[serde(default)]
+    skip_list: Option<String>,
+    #[serde(default)]
+    link_mode: LinkMode,
+    #[serde(default)]
+    jobs: Option<usize>,
+    #[serde(default)]
+    net_jobs: Option<usize>,
+    #[serde(default)]
+    ignored_paths: Vec<String>,
+    #[serde(default)]
+    follow_symlinks: bool,
+    #[serde(default)]
+    skip_hidden: bool,
+    #[serde(default)]
+    archive: Option<String>,
+    #[serde(default)]
+    commit_output: bool,
+    #[serde(default)]
+    pull_request: Option<PullRequest>,
+    #[serde(default)]
+    webhooks: Vec<notify::Webhook>,
+    #[serde(default)]
+    harness_directive: Option<String>,
+    #[serde(default)]
+    directive: Option<String>,
+    #[serde(default)]
+    directives: Vec<String>,
+    #[serde(default)]
+    auto_feature_flags: bool,
+    #[serde(default)]
+    feature_flags: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    test_directives: BTreeMap<String, String>,
+    #[serde(default)]
+    long_timeout_tests: Vec<String>,
+    #[serde(default)]
+    test_extensions: Vec<String>,
+    #[serde(default)]
+    included_tests: Vec<String>,
+    #[serde(default)]
+    excluded_tests: Vec<String>,
+    #[serde(default)]
+    excluded_dirs: Vec<String>,
+    #[serde(default)]
+    excluded_content: Vec<String>,
+    #[serde(default)]
+    url_rewrites: BTreeMap<String, String>,
+    #[serde(default)]
+    proxy: Option<String>,
+    repos: Vec<Repo>,
+}
+
+impl Config {
+    /// Load `config.toml` merged with `config.d/*.toml`, applying any `WGT_*`
+    /// environment overrides.
+    pub fn load() -> Result<Config> {
+        load_config(&Options::default())
+    }
+
+    /// The repos, in the order they must be built so that every repo is
+    /// built after its parents.
+    pub fn repos(&self) -> Result<Vec<&Repo>> {
+        sort_repos(self)
+    }
+
+    fn specs_dir(&self) -> &str {
+        self.specs_dir.as_deref().unwrap_or("specs")
+    }
+
+    fn output_dir(&self) -> &str {
+        self.output_dir.as_deref().unwrap_or("tests")
+    }
+
+    fn reports_dir(&self) -> &str {
+        self.reports_dir.as_deref().unwrap_or("reports")
+    }
+
+    fn layout(&self) -> &str {
+        self.layout.as_deref().unwrap_or(layout::DEFAULT)
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1)
+        })
+    }
+
+    // The number of repos fetched at once, which is limited separately as CI
+    // runners may throttle connections long before they run out of CPUs
+    fn net_jobs(&self) -> usize {
+        self.net_jobs.unwrap_or_else(|| self.jobs())
+    }
+
+    fn shell_timeout(&self) -> u64 {
+        self.shell_timeout.unwrap_or(60)
+    }
+
+    fn find_repo(&self, name: &str) -> Option<&Repo> {
+        self.repos.iter().find(|x| x.name == name)
+    }
+
+    fn find_repo_mut(&mut self, name: &str) -> Option<&mut Repo> {
+        self.repos.iter_mut().find(|x| &x.name == name)
+    }
+
+    // All of the transitive parents of a repo, nearest first
+    fn ancestors<'a>(&'a self, repo: &'a Repo) -> Vec<&'a str> {
+        let mut ancestors: Vec<&str> = Vec::new();
+        let mut queue: Vec<&str> = repo.parent.iter().map(|x| x.as_str()).collect();
+        while !queue.is_empty() {
+            let name = queue.remove(0);
+            if ancestors.contains(&name) {
+                continue;
+            }
+            ancestors.push(name);
+            if let Some(parent) = self.find_repo(name) {
+                queue.extend(parent.parent.iter().map(|x| x.as_str()));
+            }
+        }
+        ancestors
+    }
+
+    // The engine flags needed for the features of a repo and its ancestors,
+    // looked up by repo name in `feature_flags` and then `FEATURE_FLAGS`
+    fn feature_flags(&self, repo: &Repo) -> Vec<&str> {
+        let mut flags = Vec::new();
+        for name in std::iter::once(repo.name.as_str()).chain(self.ancestors(repo)) {
+            match self.feature_flags.get(name) {
+                Some(repo_flags) => flags.extend(repo_flags.iter().map(|x| x.as_str())),
+                None => flags.extend(
+                    FEATURE_FLAGS
+                        .iter()
+                        .filter(|(x, _)| *x == name)
+                        .map(|(_, flag)| *flag),
+                ),
+            }
+        }
+        flags
+    }
+
+    // The contents of the `directives.txt` for a repo. The directive strings
+    // are concatenated, followed by the directive lists joined with `; `.
+    fn directives(&self, repo: &Repo) -> String {
+        let mut directives = format!(
+            "{}{}",
+            self.directive.as_deref().unwrap_or(""),
+            repo.directive.as_deref().unwrap_or("")
+        );
+        let list = self.directive_list(repo);
+        if !list.is_empty() {
+            if !directives.is_empty() {
+                directives.push_str("; ");
+            }
+            directives.push_str(&list.join("; "));
+        }
+        directives
+    }
+
+    // The directive lists of the config and a repo, and any feature flags,
+    // without duplicates, in order
+    fn directive_list<'a>(&'a self, repo: &'a Repo) -> Vec<&'a str> {
+        let mut list: Vec<&str> = Vec::new();
+        let mut all: Vec<&str> = self
+            .directives
+            .iter()
+            .chain(&repo.directives)
+            .map(|x| x.as_str())
+            .collect();
+        if self.auto_feature_flags {
+            all.extend(self.feature_flags(repo));
+        }
+        for directive in all {
+            if !list.contains(&directive) {
+                list.push(directive);
+            }
+        }
+        list
+    }
+}
+
+/// A spec or proposal repo in the config
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Repo {
+    name: String,
+    url: String,
+    #[serde(default)]
+    output_name: Option<String>,
+    #[serde(default)]
+    branch: Option<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    parent: Vec<String>,
+    #[serde(default)]
+    harness_directive: Option<String>,
+    #[serde(default)]
+    directive: Option<String>,
+    #[serde(default)]
+    directives: Vec<String>,
+    #[serde(default)]
+    test_directives: BTreeMap<String, String>,
+    #[serde(default)]
+    long_timeout_tests: Vec<String>,
+    #[serde(default)]
+    test_extensions: Vec<String>,
+    #[serde(default)]
+    included_tests: Vec<String>,
+    #[serde(default)]
+    excluded_tests: Vec<String>,
+    #[serde(default)]
+    excluded_dirs: Vec<String>,
+    #[serde(default)]
+    excluded_content: Vec<String>,
+    #[serde(default)]
+    skip_wast: bool,
+    #[serde(default)]
+    skip_js: bool,
+    #[serde(default)]
+    skip_js_api: bool,
+    #[serde(default)]
+    skip_json: bool,
+    #[serde(default)]
+    extra_dirs: Vec<String>,
+    #[serde(default)]
+    variants: Vec<Variant>,
+    #[serde(default)]
+    integration: Strategy,
+    #[serde(default)]
+    auto_resolve: Option<BTreeMap<String, Side>>,
+    #[serde(default)]
+    fallback_commits: usize,
+    #[serde(default)]
+    patches: Vec<String>,
+    #[serde(default)]
+    cherry_picks: Vec<String>,
+    #[serde(default)]
+    interpreter_build: Option<String>,
+    #[serde(default)]
+    interpreter_outputs: Vec<String>,
+    #[serde(default)]
+    build_script: Option<String>,
+    #[serde(default)]
+    python: Option<String>,
+    #[serde(default)]
+    pre_build: Option<String>,
+    #[serde(default)]
+    post_build: Option<String>,
+    #[serde(default)]
+    auth: Option<Auth>,
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    fetch_depth: Option<u32>,
+    #[serde(default)]
+    fetch_tags: bool,
+    #[serde(default)]
+    full_history: bool,
+    #[serde(skip)]
+    remote_url: Option<String>,
+}
+
+// Credentials for fetching from a private remote, passed to git through the
+// environment rather than the git config
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Auth {
+    #[serde(default)]
+    token_env: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    ssh_key: Option<String>,
+    #[serde(default)]
+    credential_helper: Option<String>,
+}
+
+impl Auth {
+    // The environment to run git with to use these credentials
+    fn env(&self) -> Vec<(String, String)> {
+        let mut config = Vec::new();
+        let mut helper = |x: String| {
+            // An empty helper clears the helpers from the user's config
+            if config.is_empty() {
+                config.push(("credential.helper".to_owned(), String::new()));
+            }
+            config.push(("credential.helper".to_owned(), x));
+        };
+        if let Some(token_env) = &self.token_env {
+            // The token is read by the helper, so it's never in an argument
+            let username = self.username.as_deref().unwrap_or("x-access-token");
+            helper(format!(
+                "!f() {{ test \"$1\" = get && echo username={} && echo \"password=${}\"; }}; f",
+                username, token_env
+            ));
+        }
+        if let Some(credential_helper) = &self.credential_helper {
+            helper(credential_helper.clone());
+        }
+
+        let mut env = vec![("GIT_CONFIG_COUNT".to_owned(), config.len().to_string())];
+        for (i, (key, value)) in config.into_iter().enumerate() {
+            env.push((format!("GIT_CONFIG_KEY_{}", i), key));
+            env.push((format!("GIT_CONFIG_VALUE_{}", i), value));
+        }
+        if let Some(ssh_key) = &self.ssh_key {
+            env.push((
+                "GIT_SSH_COMMAND".to_owned(),
+                format!(
+                    "ssh -i '{}' -o IdentitiesOnly=yes",
+                    ssh_key.replace('\'', "'\\''")
+                ),
+            ));
+        }
+        env
+    }
+}
+
+impl Repo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The name of the repo's directories in the output, `name` by default
+    pub fn output_name(&self) -> &str {
+        self.output_name.as_deref().unwrap_or(&self.name)
+    }
+
+    // The URL to fetch from, after applying any rewrites
+    fn remote_url(&self) -> &str {
+        self.remote_url.as_deref().unwrap_or(&self.url)
+    }
+
+    // Merge conflicts in these paths are resolved automatically, by default
+    // only in the document directory
+    fn auto_resolve(&self) -> Vec<(&str, Side)> {
+        match &self.auto_resolve {
+            Some(paths) => paths
+                .iter()
+                .map(|(path, side)| (path.as_str(), *side))
+                .collect(),
+            None => vec![("document", Side::Ours)],
+        }
+    }
+}
+
+/// How the tests are put into the output directory. Symlinks aren't supported,
+/// as every repo shares the same worktree in the specs directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    #[default]
+    Copy,
+    Hardlink,
+}
+
+impl FromStr for LinkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(link_mode: &str) -> Result<LinkMode> {
+        match link_mode {
+            "copy" => Ok(LinkMode::Copy),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "symlink" => bail!(
+                "symlinks aren't supported as the link mode, as every repo is built in the \
+                 same worktree, use `hardlink` instead"
+            ),
+            _ => bail!("expected `copy` or `hardlink`, got `{}`", link_mode),
+        }
+    }
+}
+
+impl LinkMode {
+    fn link(self, src: &Path, dst: &Path) -> Result<()> {
+        match self {
+            LinkMode::Copy => fs::copy(src, dst).map(|_| ())?,
+            LinkMode::Hardlink => {
+                let _ = fs::remove_file(dst);
+                fs::hard_link(src, dst)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Strategy {
+    #[default]
+    Merge,
+    Rebase,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Side {
+    Ours,
+    Theirs,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PullRequest {
+    branch: String,
+    #[serde(default)]
+    remote: Option<String>,
+    #[serde(default)]
+    base: Option<String>,
+    #[serde(default)]
+    repository: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+impl PullRequest {
+    fn remote(&self) -> &str {
+        self.remote.as_deref().unwrap_or("origin")
+    }
+
+    fn title(&self) -> &str {
+        self.title
+            .as_deref()
+            .unwrap_or("Update generated wasm spec tests")
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Variant {
+    name: String,
+    #[serde(default, deserialize_with = "string_or_list")]
+    parent: Vec<String>,
+}
+
+// Accept either a single string or a list of strings
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(
+        match <StringOrList as serde::Deserialize>::deserialize(deserializer)? {
+            StringOrList::String(x) => vec![x],
+            StringOrList::List(x) => x,
+        },
+    )
+}
+
+/// The commits each repo is pinned to, from `config-lock.toml`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lock {
+    #[serde(default)]
+    version: u32,
+    repos: Vec<LockRepo>,
+}
+
+impl Lock {
+    /// Load `config-lock.toml`, migrating it from an older format if needed.
+    /// If there is no lock, no repo is pinned.
+    pub fn load() -> Result<Lock> {
+        load_lock()
+    }
+
+    /// Write `config-lock.toml`, keeping the previous lock as a backup.
+    pub fn write(&self) -> Result<()> {
+        write_lock(self)
+    }
+
+    /// The commit a repo is pinned to, if any
+    pub fn commit(&self, name: &str) -> Option<&str> {
+        self.find_commit(name)
+    }
+
+    fn find_commit(&self, name: &str) -> Option<&str> {
+        self.repos
+            .iter()
+            .find(|x| &x.name == name)
+            .map(|x| x.commit.as_ref())
+    }
+
+    // Remove the entries of repos that are no longer in the config, returning
+    // their names
+    fn prune(&mut self, config: &Config) -> Vec<String> {
+        let (kept, stale): (Vec<LockRepo>, Vec<LockRepo>) = self
+            .repos
+            .drain(..)
+            .partition(|x| config.find_repo(&x.name).is_some());
+        self.repos = kept;
+        stale.into_iter().map(|x| x.name).collect()
+    }
+
+    fn find_upstream(&self, name: &str) -> Option<&str> {
+        self.repos
+            .iter()
+            .find(|x| x.name == name)
+            .and_then(|x| x.upstream.as_deref())
+    }
+
+    fn set_commit(&mut self, name: &str, commit: &str, merged: &str, upstream: &str) {
+        let repo = LockRepo {
+            name: name.to_owned(),
+            commit: commit.to_owned(),
+            merged: Some(merged.to_owned()),
+            upstream: Some(upstream.to_owned()),
+        };
+        if let Some(lock) = self.repos.iter_mut().find(|x| &x.name == name) {
+            *lock = repo;
+        } else {
+            self.repos.push(repo);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LockRepo {
+    name: String,
+    commit: String,
+    /// The commit the tests were built from, after merging with any parents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merged: Option<String>,
+    /// The head of the upstream branch when the lock was updated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+}
+
+// Describes how the output tests were generated, written alongside them
+#[derive(Debug, Serialize, Deserialize)]
+struct Version {
+    version: String,
+    generated: String,
+    repos: Vec<VersionRepo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionRepo {
+    name: String,
+    commit: String,
+    merged: String,
+    built: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Merge {
+    Standalone,
+    Merged,
+    Rebased,
+    Conflicted(Conflict),
+}
+
+impl Merge {
+    fn name(&self) -> &'static str {
+        match self {
+            Merge::Standalone => "standalone",
+            Merge::Merged => "merged",
+            Merge::Rebased => "rebased",
+            Merge::Conflicted(_) => "conflicted",
+        }
+    }
+}
+
+impl Status {
+    /// The commit of the repo the tests were built from, before merging
+    pub fn commit(&self) -> &str {
+        &self.commit_base_hash
+    }
+
+    /// The commit the tests were built from, after merging with any parents
+    pub fn merged_commit(&self) -> &str {
+        &self.commit_final_hash
+    }
+
+    /// How the repo was integrated with its parents, either "standalone",
+    /// "merged", "rebased" or "conflicted"
+    pub fn merged(&self) -> &str {
+        self.merged.name()
+    }
+
+    /// Whether the tests built, rather than only the `.wast` tests being copied
+    pub fn built(&self) -> bool {
+        self.built
+    }
+
+    /// Anything notable about the build, such as falling back to an older
+    /// commit
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+}
+
+// The details of a failed merge or rebase with a parent
+#[derive(Debug, Serialize, Deserialize)]
+struct Conflict {
+    parent: String,
+    files: Vec<String>,
+    diff: String,
+}
+
+impl Conflict {
+    // Capture the conflicts of an in-progress merge or rebase
+    fn capture(parent: &str) -> Result<Conflict> {
+        Ok(Conflict {
+            parent: parent.to_owned(),
+            files: run("git", &["diff", "--name-only", "--diff-filter=U"])?
+                .lines()
+                .map(|x| x.to_owned())
+                .collect(),
+            diff: run("git", &["diff", "--diff-filter=U"])?,
+        })
+    }
+
+    fn to_markdown(&self, name: &str, commit_base_hash: &str) -> String {
+        let mut text = format!(
+            "# Conflicts merging {} with {}\n\nBase commit: {}\n\n## Conflicted files\n\n",
+            name, self.parent, commit_base_hash
+        );
+        for file in &self.files {
+            text.push_str(&format!("- `{}`\n", file));
+        }
+        text.push_str(&format!("\n## Diff\n\n```diff\n{}\n```\n", self.diff));
+        text
+    }
+}
+
+/// The result of building the tests of a repo
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    branch: String,
+    commit_base_hash: String,
+    commit_final_hash: String,
+    commit_upstream_hash: String,
+    commit_final_message: String,
+    merged: Merge,
+    built: bool,
+    build_log: Option<String>,
+    #[serde(default)]
+    fallback_js: bool,
+    variants: Vec<VariantStatus>,
+    notes: Vec<String>,
+    #[serde(default)]
+    removed_tests: Vec<RemovedTest>,
+}
+
+// A `.wast` test that upstream removed or renamed since the previous tests
+#[derive(Debug, Serialize, Deserialize)]
+struct RemovedTest {
+    path: String,
+    renamed: Option<String>,
+}
+
+// The result of building a variant of a repo
+#[derive(Debug, Serialize, Deserialize)]
+struct VariantStatus {
+    name: String,
+    merged: Merge,
+    built: bool,
+    commit: String,
+    build_log: Option<String>,
+    #[serde(default)]
+    fallback_js: bool,
+}
+
+// The kinds of tests that are output for each repo, into `${kind}/${repo}`
+const KINDS: &[&str] = &["wast", "js", "js-api", "json", "extra"];
+
+// The files of directives written into the output directories of a repo,
+// which aren't tests
+const DIRECTIVES_FILES: &[&str] = &["directives.txt", "__dir__.ini"];
+
+// The engine flags enabling each proposal, by repo name
+const FEATURE_FLAGS: &[(&str, &str)] = &[
+    ("exception-handling", "--wasm-exceptions"),
+    ("extended-const", "--wasm-extended-const"),
+    ("function-references", "--wasm-function-references"),
+    ("gc", "--wasm-gc"),
+    ("js-string-builtins", "--wasm-js-string-builtins"),
+    ("memory-control", "--wasm-memory-control"),
+    ("memory64", "--wasm-memory64"),
+    ("multi-memory", "--wasm-multi-memory"),
+    ("relaxed-simd", "--wasm-relaxed-simd"),
+    ("tail-call", "--wasm-tail-calls"),
+];
+
+// Roll-your-own CLI utilities
+
+fn run(name: &str, args: &[&str]) -> Result<String> {
+    run_env(name, args, &[])
+}
+
+// Run a command whose exit code has a meaning, returning its exit code and
+// output whether it succeeds or not
+fn run_status(name: &str, args: &[&str]) -> Result<runner::Output> {
+    runner::current().run_status(name, args, &[])
+}
+
+// Run git for an operation on the remote of a repo, with its credentials and
+// proxy
+fn run_remote(repo: &Repo, args: &[&str]) -> Result<String> {
+    let proxy = repo.proxy.as_ref().map(|x| format!("http.proxy={}", x));
+    let mut remote_args = Vec::new();
+    if let Some(proxy) = &proxy {
+        remote_args.extend(["-c", proxy.as_str()]);
+    }
+    remote_args.extend(args);
+    match &repo.auth {
+        Some(auth) => run_env("git", &remote_args, &auth.env()),
+        None => run("git", &remote_args),
+    }
+}
+
+fn run_env(name: &str, args: &[&str], env: &[(String, String)]) -> Result<String> {
+    runner::current().run(name, args, env)
+}
+
+// Run a long-running command, showing its output as it's written
+fn run_streamed(name: &str, args: &[&str]) -> Result<String> {
+    runner::current().run_streamed(name, args, &[])
+}
+
+// Emit a GitHub Actions workflow command for an error or warning, so that it's
+// shown in the checks UI. Does nothing outside of GitHub Actions.
+fn annotate(level: &str, title: &str, message: &str) {
+    if env::var("GITHUB_ACTIONS").as_deref() != Ok("true") {
+        return;
+    }
+    let escape = |x: &str| {
+        x.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    println!(
+        "::{} title={}::{}",
+        level,
+        escape(title).replace(':', "%3A").replace(',', "%2C"),
+        escape(message)
+    );
+}
+
+fn change_dir(dir: &str) -> impl Drop {
+    #[must_use]
+    struct Reset {
+        previous: PathBuf,
+    }
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            debug!("cd {}", self.previous.display());
+            env::set_current_dir(&self.previous).unwrap()
+        }
+    }
+
+    let previous = Reset {
+        previous: env::current_dir().unwrap(),
+    };
+    debug!("cd {}", dir);
+    env::set_current_dir(dir).unwrap();
+    previous
+}
+
+fn find(dir: &str) -> Vec<PathBuf> {
+    Walk::default().find(dir)
+}
+
+/// Builds the tests of the repos in a config, for embedding test generation in
+/// another tool.
+pub struct Builder {
+    config: Config,
+    lock: Lock,
+}
+
+impl Builder {
+    /// A builder for a config, with no repo pinned
+    pub fn new(config: Config) -> Builder {
+        Builder {
+            config,
+            lock: empty_lock(),
+        }
+    }
+
+    /// Build the repos at the commits pinned by a lock, where pinned
+    pub fn with_lock(mut self, lock: Lock) -> Builder {
+        self.lock = lock;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn lock(&self) -> &Lock {
+        &self.lock
+    }
+
+    /// The names of the repos, in the order they must be built
+    pub fn repos(&self) -> Result<Vec<String>> {
+        Ok(self
+            .config
+            .repos()?
+            .iter()
+            .map(|x| x.name.clone())
+            .collect())
+    }
+
+    /// Fetch a repo, merge it with its parents and copy its tests into
+    /// `output_dir`. The specs directory is created if needed. The parents of
+    /// a repo must be built first.
+    pub fn build_repo(&self, name: &str, output_dir: &Path) -> Result<Status> {
+        let repo = self
+            .config
+            .find_repo(name)
+            .with_context(|| format!("unknown repo `{}`", name))?;
+        let output_dir = env::current_dir()?.join(output_dir);
+        let specs_dir = self.config.specs_dir();
+        if !Path::new(specs_dir).exists() {
+            fs::create_dir_all(specs_dir)?;
+            run("git", &["-C", specs_dir, "init"])?;
+        }
+
+        let _cd = change_dir(specs_dir);
+        recover_specs()?;
+        let branch_upstream = fetch_repo(repo, &self.lock)?;
+        build_repo(
+            repo,
+            &branch_upstream,
+            &self.config,
+            &self.lock,
+            &output_dir,
+        )
+    }
+
+    /// Pin a repo to the commits it was built from
+    pub fn update_lock(&mut self, name: &str, status: &Status) {
+        self.lock.set_commit(
+            name,
+            &status.commit_base_hash,
+            &status.commit_final_hash,
+            &status.commit_upstream_hash,
+        );
+    }
+}
+
+// Options for walking a directory tree. `.git` directories are always skipped.
+#[derive(Debug, Default)]
+struct Walk {
+    /// Paths relative to the root to skip, along with anything below them
+    ignore: Vec<Regex>,
+    /// Whether to walk into symlinked directories, instead of returning
+    /// symlinks as files
+    follow_symlinks: bool,
+    /// Whether to skip files and directories starting with a `.`
+    skip_hidden: bool,
+}
+
+impl Walk {
+    fn from_config(config: &Config) -> Result<Walk> {
+        Ok(Walk {
+            ignore: config
+                .ignored_paths
+                .iter()
+                .map(|x| Regex::new(x))
+                .collect::<Result<_, _>>()?,
+            follow_symlinks: config.follow_symlinks,
+            skip_hidden: config.skip_hidden,
+        })
+    }
+
+    // Every file below a directory, in sorted order. Entries that can't be
+    // read are skipped with a warning.
+    fn find(&self, dir: &str) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let mut visited = Vec::new();
+        self.walk(Path::new(dir), Path::new(dir), &mut visited, &mut paths);
+
+        // Sort the paths, so that everything built from them is reproducible
+        paths.sort();
+        paths
+    }
+
+    fn walk(&self, root: &Path, dir: &Path, visited: &mut Vec<PathBuf>, paths: &mut Vec<PathBuf>) {
+        // Don't walk through a symlink loop forever
+        if self.follow_symlinks {
+            match fs::canonicalize(dir) {
+                Ok(dir) if visited.contains(&dir) => return,
+                Ok(dir) => visited.push(dir),
+                Err(err) => warn!("Skipping {}: {}", dir.display(), err),
+            }
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Skipping {}: {}", dir.display(), err);
+                return;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Skipping an entry of {}: {}", dir.display(), err);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let relative = path.strip_prefix(root).unwrap().to_string_lossy();
+            if name == ".git"
+                || (self.skip_hidden && name.starts_with('.'))
+                || self.ignore.iter().any(|x| x.is_match(&relative))
+            {
+                continue;
+            }
+
+            let is_dir = match entry.file_type() {
+                Ok(file_type) if file_type.is_symlink() && self.follow_symlinks => path.is_dir(),
+                Ok(file_type) => file_type.is_dir(),
+                Err(err) => {
+                    warn!("Skipping {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            if is_dir {
+                self.walk(root, &path, visited, paths);
+            } else {
+                paths.push(path);
+            }
+        }
+    }
+}
+
+// Split the items into a chunk for each job, and map each chunk on its own
+// thread. The results are in the same order as the chunks.
+fn parallel_map<T, R, F>(jobs: usize, items: &[T], f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&[T]) -> Result<R> + Sync,
+{
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+    let jobs = jobs.max(1);
+    let chunk_size = items.len().div_ceil(jobs);
+    let f = &f;
+    let runner = runner::current();
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let runner = runner.clone();
+                scope.spawn(move || {
+                    runner::set_runner(runner);
+                    f(chunk)
+                })
+            })
+            .collect();
+        threads.into_iter().map(|x| x.join().unwrap()).collect()
+    })
+}
+
+// Expand a path whose file name may contain `*` wildcards, in sorted order
+fn glob(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let name = match pattern.file_name().and_then(|x| x.to_str()) {
+        Some(name) if name.contains('*') => name,
+        _ => return Ok(vec![pattern.to_owned()]),
+    };
+    let dir = pattern.parent().unwrap_or_else(|| Path::new("."));
+    let regex = Regex::new(&format!("^{}$", regex::escape(name).replace("\\*", ".*")))?;
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_str()
+            .is_some_and(|x| regex.is_match(x))
+        {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn write_string<P: AsRef<Path>>(path: P, text: &str) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    fs::write(path, text.as_bytes())?;
+    Ok(())
+}
+
+// A directory of config files merged into `config.toml`, so entries can be
+// added and removed without rewriting a shared file
+const CONFIG_DIR: &str = "config.d";
+
+fn load_config(options: &Options) -> Result<Config> {
+    let table = read_config()?;
+    let mut config: Config = toml::Value::Table(table)
+        .try_into()
+        .context("invalid config")?;
+    config.root = env::current_dir()?;
+    apply_env_overrides(&mut config)?;
+    apply_options(&mut config, options)?;
+
+    // Rewrite the URLs to fetch from, using the longest matching prefix like
+    // git's `insteadOf`
+    for repo in &mut config.repos {
+        let rewrite = config
+            .url_rewrites
+            .iter()
+            .filter(|(prefix, _)| repo.url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len());
+        if let Some((prefix, replacement)) = rewrite {
+            repo.remote_url = Some(format!("{}{}", replacement, &repo.url[prefix.len()..]));
+        }
+        if repo.proxy.is_none() {
+            repo.proxy = config.proxy.clone();
+        }
+    }
+    Ok(config)
+}
+
+// Read `config.toml`, if there's a config directory, and every `*.toml` in the
+// config directory in order of their names, into one table. The repos of each
+// file are appended in turn, and any other setting may only be set by one file.
+fn read_config() -> Result<toml::value::Table> {
+    let mut paths = Vec::new();
+    if Path::new("config.toml").exists() || !Path::new(CONFIG_DIR).exists() {
+        paths.push(PathBuf::from("config.toml"));
+    }
+    if Path::new(CONFIG_DIR).exists() {
+        let mut extra = Vec::new();
+        for entry in fs::read_dir(CONFIG_DIR)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("toml")) {
+                extra.push(path);
+            }
+        }
+        extra.sort();
+        paths.extend(extra);
+    }
+
+    let mut config = toml::value::Table::new();
+    let mut origins = HashMap::new();
+    for path in &paths {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let table = toml::from_str(&text).with_context(|| format!("invalid {}", path.display()))?;
+        merge_config(&mut config, table, "", path, &mut origins)?;
+    }
+    Ok(config)
+}
+
+// Merge the settings of a config file into those read so far, recording which
+// file set each one in `origins`
+fn merge_config(
+    config: &mut toml::value::Table,
+    table: toml::value::Table,
+    prefix: &str,
+    path: &Path,
+    origins: &mut HashMap<String, PathBuf>,
+) -> Result<()> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key);
+        if name == "repos" {
+            let repos = config
+                .entry(key)
+                .or_insert_with(|| toml::Value::Array(Vec::new()));
+            let (repos, more) = match (repos, value) {
+                (toml::Value::Array(repos), toml::Value::Array(more)) => (repos, more),
+                _ => bail!("`repos` must be an array in {}", path.display()),
+            };
+            for repo in more {
+                // Duplicates within a file are left to `config check`
+                let repo_name = repo.get("name").and_then(|x| x.as_str()).unwrap_or("");
+                let origin = format!("repos.{}", repo_name);
+                if let Some(first) = origins.insert(origin, path.to_owned()) {
+                    if first != path {
+                        bail!(
+                            "repo `{}` is defined in both {} and {}",
+                            repo_name,
+                            first.display(),
+                            path.display()
+                        );
+                    }
+                }
+                repos.push(repo);
+            }
+            continue;
+        }
+
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(settings)), toml::Value::Table(more)) => {
+                merge_config(settings, more, &format!("{}.", name), path, origins)?;
+            }
+            (Some(_), _) => {
+                let first = origins
+                    .iter()
+                    .filter(|(x, _)| name == **x || name.starts_with(&format!("{}.", x)))
+                    .map(|(_, first)| first.display().to_string())
+                    .next()
+                    .unwrap_or_default();
+                bail!("`{}` is set in both {} and {}", name, first, path.display());
+            }
+            (None, value) => {
+                origins.insert(name, path.to_owned());
+                config.insert(key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Allow CI to tweak settings through `WGT_*` variables without patching the
+// checked-in config. Per-repo variables use the uppercased repo name with
+// dashes replaced by underscores, e.g. `WGT_SIGN_EXTENSION_OPS_SKIP_JS`.
+fn apply_env_overrides(config: &mut Config) -> Result<()> {
+    if let Ok(specs_dir) = env::var("WGT_SPECS_DIR") {
+        config.specs_dir = Some(specs_dir);
+    }
+    if let Ok(output_dir) = env::var("WGT_OUTPUT_DIR") {
+        config.output_dir = Some(output_dir);
+    }
+    if let Ok(reports_dir) = env::var("WGT_REPORTS_DIR") {
+        config.reports_dir = Some(reports_dir);
+    }
+    if let Ok(layout) = env::var("WGT_LAYOUT") {
+        config.layout = Some(layout);
+    }
+    if let Ok(harness_directive) = env::var("WGT_HARNESS_DIRECTIVE") {
+        config.harness_directive = Some(harness_directive);
+    }
+    if let Ok(directive) = env::var("WGT_DIRECTIVE") {
+        config.directive = Some(directive);
+    }
+    if let Ok(jobs) = env::var("WGT_JOBS") {
+        match jobs.parse() {
+            Ok(jobs) if jobs > 0 => config.jobs = Some(jobs),
+            _ => bail!("invalid value for WGT_JOBS: `{}`", jobs),
+        }
+    }
+    if let Ok(net_jobs) = env::var("WGT_NET_JOBS") {
+        match net_jobs.parse() {
+            Ok(net_jobs) if net_jobs > 0 => config.net_jobs = Some(net_jobs),
+            _ => bail!("invalid value for WGT_NET_JOBS: `{}`", net_jobs),
+        }
+    }
+    if let Ok(proxy) = env::var("WGT_PROXY") {
+        config.proxy = Some(proxy);
+    }
+    if let Ok(features) = env::var("WGT_FEATURES") {
+        config.features = Some(split_list(&features));
+    }
+    if let Ok(excluded_features) = env::var("WGT_EXCLUDED_FEATURES") {
+        config.excluded_features = split_list(&excluded_features);
+    }
+    if let Ok(archive) = env::var("WGT_ARCHIVE") {
+        config.archive = Some(archive);
+    }
+    if let Some(commit_output) = env_bool("WGT_COMMIT_OUTPUT")? {
+        config.commit_output = commit_output;
+    }
+    if env_bool("WGT_WEBHOOKS")? == Some(false) {
+        config.webhooks.clear();
+    }
+    if let Ok(link_mode) = env::var("WGT_LINK_MODE") {
+        config.link_mode = link_mode
+            .parse()
+            .context("invalid value for WGT_LINK_MODE")?;
+    }
+
+    let skip_wast = env_bool("WGT_SKIP_WAST")?;
+    let skip_js = env_bool("WGT_SKIP_JS")?;
+    let skip_js_api = env_bool("WGT_SKIP_JS_API")?;
+    let skip_json = env_bool("WGT_SKIP_JSON")?;
+    for repo in &mut config.repos {
+        let prefix = format!("WGT_{}_", repo.name.to_uppercase().replace('-', "_"));
+        if let Ok(harness_directive) = env::var(format!("{}HARNESS_DIRECTIVE", prefix)) {
+            repo.harness_directive = Some(harness_directive);
+        }
+        if let Ok(directive) = env::var(format!("{}DIRECTIVE", prefix)) {
+            repo.directive = Some(directive);
+        }
+        if let Ok(proxy) = env::var(format!("{}PROXY", prefix)) {
+            repo.proxy = Some(proxy);
+        }
+        if let Some(skip) = env_bool(&format!("{}SKIP_WAST", prefix))?.or(skip_wast) {
+            repo.skip_wast = skip;
+        }
+        if let Some(skip) = env_bool(&format!("{}SKIP_JS", prefix))?.or(skip_js) {
+            repo.skip_js = skip;
+        }
+        if let Some(skip) = env_bool(&format!("{}SKIP_JS_API", prefix))?.or(skip_js_api) {
+            repo.skip_js_api = skip;
+        }
+        if let Some(skip) = env_bool(&format!("{}SKIP_JSON", prefix))?.or(skip_json) {
+            repo.skip_json = skip;
+        }
+    }
+
+    if let Ok(only) = env::var("WGT_ONLY") {
+        only_kinds(config, &split_list(&only), "WGT_ONLY")?;
+    }
+    Ok(())
+}
+
+// Apply the options passed on the command line, which take precedence over
+// the config and its environment overrides
+fn apply_options(config: &mut Config, options: &Options) -> Result<()> {
+    if let Some(link_mode) = options.link_mode {
+        config.link_mode = link_mode;
+    }
+    if let Some(features) = &options.features {
+        config.features = Some(features.clone());
+    }
+    if let Some(excluded_features) = &options.excluded_features {
+        config.excluded_features = excluded_features.clone();
+    }
+    if let Some(net_jobs) = options.net_jobs {
+        config.net_jobs = Some(net_jobs.get());
+    }
+    if let Some(archive) = &options.archive {
+        config.archive = Some(archive.clone());
+    }
+    if let Some(only) = &options.only {
+        only_kinds(config, only, "--only")?;
+    }
+    Ok(())
+}
+
+// Only output the listed kinds, whatever each repo skips. `source` is where
+// the list came from, for errors.
+fn only_kinds(config: &mut Config, only: &[String], source: &str) -> Result<()> {
+    for kind in only {
+        if !["wast", "js", "js-api", "json"].contains(&kind.as_str()) {
+            bail!(
+                "invalid kind in {}: `{}`, expected wast, js, js-api or json",
+                source,
+                kind
+            );
+        }
+    }
+    let skip = |kind: &str| !only.iter().any(|x| x == kind);
+    for repo in &mut config.repos {
+        repo.skip_wast = skip("wast");
+        repo.skip_js = skip("js");
+        repo.skip_js_api = skip("js-api");
+        repo.skip_json = skip("json");
+    }
+    Ok(())
+}
+
+// Split a comma separated list from the environment
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>> {
+    match env::var(name) {
+        Ok(value) => match value.as_str() {
+            "1" | "true" => Ok(Some(true)),
+            "0" | "false" => Ok(Some(false)),
+            _ => bail!("invalid value for {}: `{}`", name, value),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// The version of the lock format written. Older locks are migrated when
+// they're loaded:
+//  1. No `version`, and only the `commit` of each repo
+//  2. Adds the `merged` and `upstream` commits of each repo
+const LOCK_VERSION: u32 = 2;
+
+// A lock with no pinned commits
+fn empty_lock() -> Lock {
+    Lock {
+        version: LOCK_VERSION,
+        ..Lock::default()
+    }
+}
+
+fn load_lock() -> Result<Lock> {
+    // Default to no pinned commits if there is no lock file
+    if !Path::new("config-lock.toml").exists() {
+        return Ok(empty_lock());
+    }
+    let text = fs::read_to_string("config-lock.toml").context("failed to read config-lock.toml")?;
+    let mut value: toml::Value = toml::from_str(&text).context("invalid config-lock.toml")?;
+    let version = match value.get("version") {
+        None => 1,
+        Some(toml::Value::Integer(version)) if *version > 0 => *version as u32,
+        Some(version) => bail!("invalid config-lock.toml: unknown version `{}`", version),
+    };
+    if version > LOCK_VERSION {
+        bail!(
+            "config-lock.toml has version {}, but only versions up to {} are supported, update wasm-generate-testsuite",
+            version,
+            LOCK_VERSION
+        );
+    }
+    for version in version..LOCK_VERSION {
+        migrate_lock(&mut value, version);
+    }
+
+    let mut lock: Lock = value.try_into().context("invalid config-lock.toml")?;
+    lock.version = LOCK_VERSION;
+    Ok(lock)
+}
+
+// Upgrade a lock from `version` to the next version
+fn migrate_lock(_value: &mut toml::Value, version: u32) {
+    match version {
+        // The new fields are optional, and are filled in the next time the
+        // lock is written
+        1 => {}
+        _ => unreachable!(),
+    }
+}
+
+// Write the lock, keeping the previous one in `config-lock.toml.bak`. The lock
+// is written to a temporary file first and renamed over the old one, so that
+// it's never left half written.
+fn write_lock(lock: &Lock) -> Result<()> {
+    let text = toml::to_string_pretty(lock)?;
+    if Path::new("config-lock.toml").exists() {
+        fs::copy("config-lock.toml", "config-lock.toml.bak")
+            .context("failed to back up config-lock.toml")?;
+    }
+    write_atomic("config-lock.toml", &text)
+}
+
+// Write a file by renaming a temporary file over it, once it's on disk
+fn write_atomic(path: &str, text: &str) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp).with_context(|| format!("failed to create {}", tmp))?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to write {}", path))
+}
+
+// The main script
+
+/// Validate `config.toml` and `config-lock.toml` without touching the network,
+/// printing every problem and failing if there were any.
+pub fn config_check(options: &Options) -> Result<()> {
+    // Report load failures as problems too
+    let mut problems = Vec::new();
+    let config = load_config(options)
+        .map_err(|err| problems.push(format!("{:#}", err)))
+        .ok();
+    let lock = load_lock()
+        .map_err(|err| problems.push(format!("{:#}", err)))
+        .ok();
+    if let (Some(config), Some(lock)) = (&config, &lock) {
+        problems.extend(check::check(config, lock));
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("error: {}", problem);
+            annotate("error", "config check", problem);
+        }
+        bail!("found {} problems in the config", problems.len());
+    }
+    println!("ok");
+    Ok(())
+}
+
+/// Regenerate the tests into a scratch directory, and fail if they differ from
+/// the current tests or the lock would change.
+pub fn verify(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let lock = fs::read_to_string("config-lock.toml").ok();
+    let output_dir = config.output_dir();
+    let verify_dir = format!("{}.verify", output_dir);
+
+    // Only generate the tests, leaving the current ones and everything they're
+    // published to alone
+    let mut verify_config = load_config(options)?;
+    verify_config.output_dir = Some(verify_dir.clone());
+    verify_config.reports_dir = Some(format!("{}.verify", config.reports_dir()));
+    verify_config.commit_output = false;
+    verify_config.pull_request = None;
+    verify_config.webhooks.clear();
+    verify_config.archive = None;
+    verify_config.gc_interval_days = None;
+    // Running the tests updates the expectations and the skip list
+    verify_config.shell = None;
+    generate_with(verify_config, options, false)?;
+
+    // Leave the lock as it was, as this only checks the tests
+    let new_lock = fs::read_to_string("config-lock.toml").ok();
+    if new_lock != lock {
+        match &lock {
+            Some(lock) => write_atomic("config-lock.toml", lock)?,
+            None => fs::remove_file("config-lock.toml")?,
+        }
+        bail!("the lock changed, pin every repo to verify the tests");
+    }
+
+    match diff::diff(Path::new(output_dir), Path::new(&verify_dir), &config)? {
+        None => {
+            let _ = fs::remove_dir_all(&verify_dir);
+            println!("ok");
+            Ok(())
+        }
+        Some(report) => {
+            print!("{}", report);
+            bail!("the generated tests differ from '{}'", output_dir);
+        }
+    }
+}
+
+/// Print the tests added, removed or modified since `old_dir`.
+pub fn diff(options: &Options, old_dir: &str) -> Result<()> {
+    let config = load_config(options)?;
+    match diff::diff(Path::new(old_dir), Path::new(config.output_dir()), &config)? {
+        Some(report) => print!("{}", report),
+        None => println!("No changes."),
+    }
+    Ok(())
+}
+
+/// Print where the generated files matching `test` came from.
+pub fn which(options: &Options, test: &str) -> Result<()> {
+    let config = load_config(options)?;
+    match which::which(&config, test)? {
+        Some(report) => print!("{}", report),
+        None => bail!("no generated test matches '{}'", test),
+    }
+    Ok(())
+}
+
+/// Report the upstream changes of every repo since its locked commit.
+pub fn status(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let lock = load_lock()?;
+    let _specs_lock = lock_specs(config.specs_dir(), options)?;
+    let mut failed = Vec::new();
+    for (name, pending) in upstream::pending(&config, &lock)? {
+        match pending {
+            Ok(pending) => match &pending.pinned {
+                None => println!("{}: not locked, upstream at {}", name, pending.upstream),
+                Some(pinned) if pending.commits == 0 => {
+                    println!("{}: up to date at {}", name, pinned)
+                }
+                Some(pinned) => {
+                    println!(
+                        "{}: {} commits ahead of {} at {}, {}",
+                        name,
+                        pending.commits,
+                        pinned,
+                        pending.upstream,
+                        if pending.tests_changed.is_empty() {
+                            "test/ unchanged".to_owned()
+                        } else {
+                            format!("{} files changed in test/", pending.tests_changed.len())
+                        }
+                    );
+                    for file in &pending.tests_changed {
+                        println!("    {}", file);
+                    }
+                }
+            },
+            Err(err) => {
+                eprintln!("error: {}: {:?}", name, err);
+                failed.push(name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to check {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Remove everything left behind by repos that were removed from the config.
+pub fn prune(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let mut lock = load_lock()?;
+    let _specs_lock = if Path::new(config.specs_dir()).exists() {
+        Some(lock_specs(config.specs_dir(), options)?)
+    } else {
+        None
+    };
+    for name in lock.prune(&config) {
+        println!("removed {} from the lock", name);
+    }
+    write_lock(&lock)?;
+
+    for removed in prune::prune(&config)? {
+        println!("removed {}", removed);
+    }
+    Ok(())
+}
+
+/// Print the tests that would be generated for every repo, optionally as JSON,
+/// without building anything.
+pub fn list_tests(options: &Options, json: bool) -> Result<()> {
+    let config = load_config(options)?;
+    let lock = load_lock()?;
+    let _specs_lock = lock_specs(config.specs_dir(), options)?;
+    let listings = list::list_tests(&config, &lock)?;
+    if json {
+        println!("{}", list::to_json(&listings));
+        return Ok(());
+    }
+    for listing in &listings {
+        for test in listing.tests.values().flatten() {
+            println!("{}", test);
+        }
+    }
+    Ok(())
+}
+
+/// Print config entries for the proposals that aren't being tracked.
+pub fn discover(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let names = discover::discover(&config)?;
+    if names.is_empty() {
+        println!("Every proposal is tracked.");
+    } else {
+        eprintln!("Untracked proposals: {}", names.join(", "));
+        print!("{}", discover::scaffold(&config, &names));
+    }
+    Ok(())
+}
+
+/// Remove the objects in the specs directory that are no longer reachable.
+pub fn gc(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let lock = load_lock()?;
+    if !Path::new(config.specs_dir()).exists() {
+        println!("no specs directory");
+        return Ok(());
+    }
+    let _specs_lock = lock_specs(config.specs_dir(), options)?;
+    let _cd = change_dir(config.specs_dir());
+    gc_specs(&lock)
+}
+
+/// Print the first upstream commit of a repo that fails to build.
+pub fn bisect(options: &Options, name: &str) -> Result<()> {
+    let config = load_config(options)?;
+    let lock = load_lock()?;
+    let _specs_lock = if Path::new(config.specs_dir()).exists() {
+        Some(lock_specs(config.specs_dir(), options)?)
+    } else {
+        None
+    };
+    let commit = bisect::bisect(&config, &lock, name)?;
+    println!("first bad commit: {}", commit);
+    Ok(())
+}
+
+/// Generate the tests of every repo in `config.toml`, and update the lock.
+/// Fails, keeping the previous tests, if any repo fails.
+pub fn generate(options: &Options) -> Result<()> {
+    let config = load_config(options)?;
+    let resume = options.resume || env_bool("WGT_RESUME")?.unwrap_or(false);
+    generate_with(config, options, resume)
+}
+
+// Generate the tests with a config that may differ from `config.toml`,
+// continuing the previous run if `resume` is set
+fn generate_with(mut config: Config, options: &Options, resume: bool) -> Result<()> {
+    let mut lock = load_lock()?;
+    let previous_lock = load_lock()?;
+    set_git_identity();
+    let _specs_lock = lock_specs(config.specs_dir(), options)?;
+    interrupt::install();
+
+    // Clean old tests and initialize the repo if it doesn't exist. The tests
+    // are generated into a staging directory, and only update the previous
+    // tests if everything succeeds.
+    let specs_dir = config.specs_dir();
+    let specs_path = env::current_dir()?.join(specs_dir);
+    let final_dir = env::current_dir()?.join(config.output_dir());
+    let output_dir = PathBuf::from(format!("{}.tmp", final_dir.display()));
+    let reports_dir = env::current_dir()?.join(config.reports_dir());
+    let discard =
+        options.discard_local_changes || env_bool("WGT_DISCARD_LOCAL_CHANGES")?.unwrap_or(false);
+    let mut resumed = clean_and_init_dirs(specs_dir, &output_dir, &reports_dir, resume, discard)?;
+
+    // Remember how the previous tests were generated, to notify about repos
+    // that newly fail to merge or build
+    let previous_version: Option<Version> = fs::read_to_string(final_dir.join("VERSION.toml"))
+        .ok()
+        .and_then(|x| toml::from_str(&x).ok());
+
+    // Generate the tests
+    let mut successes = Vec::new();
+    let mut failures = Vec::new();
+    let mut integration = None;
+    let mut manifest = manifest::Manifest::default();
+    {
+        // Change to the `specs/` dir where all the work happens
+        let _cd = change_dir(specs_dir);
+        let repos = sort_repos(&config)?;
+
+        // Fetch everything first, as the network dominates at the start
+        let to_build: Vec<&Repo> = repos
+            .iter()
+            .copied()
+            .filter(|x| !resumed.contains_key(&x.name))
+            .collect();
+        let mut fetched = fetch_repos(&to_build, &lock, config.net_jobs())?;
+
+        for repo in repos {
+            stop_if_interrupted(&specs_path)?;
+            if let Some(status) = resumed.remove(&repo.name) {
+                info!("Skipping {}, completed by the previous run", repo.name);
+                successes.push((repo.name.clone(), status));
+                continue;
+            }
+            info!("Processing {:#?}", repo);
+            let branch_upstream = match fetched.remove(&repo.name).unwrap() {
+                Ok(branch_upstream) => branch_upstream,
+                Err(err) => {
+                    failures.push((repo.name.clone(), err));
+                    continue;
+                }
+            };
+
+            // Remove anything left by a previous attempt
+            clean_repo_output(repo, &output_dir);
+            for variant in &repo.variants {
+                clean_repo_output(repo, &output_dir.join(&variant.name));
+            }
+            let result = build_repo(repo, &branch_upstream, &config, &lock, &output_dir);
+            // The signal also reaches the commands being run, so don't record a
+            // merge or build it cut short
+            stop_if_interrupted(&specs_path)?;
+            match result {
+                Ok(mut status) => {
+                    // Note the tests upstream dropped since the previous tests
+                    // were generated, or since the previous lock
+                    let previous = previous_version
+                        .as_ref()
+                        .and_then(|x| x.repos.iter().find(|x| x.name == repo.name))
+                        .map(|x| x.commit.as_str())
+                        .or_else(|| previous_lock.find_commit(&repo.name));
+                    if let Some(previous) = previous {
+                        match removed_tests(previous, &status.commit_base_hash) {
+                            Ok(removed) => status.removed_tests = removed,
+                            Err(err) => warn!(
+                                "{}: failed to compare with {}: {:?}",
+                                repo.name, previous, err
+                            ),
+                        }
+                    }
+                    for test in &status.removed_tests {
+                        match &test.renamed {
+                            Some(renamed) => {
+                                warn!("{}: {} renamed to {}", repo.name, test.path, renamed)
+                            }
+                            None => warn!("{}: {} removed", repo.name, test.path),
+                        }
+                    }
+                    successes.push((repo.name.clone(), status));
+                    if let Err(err) = write_run_state(&successes) {
+                        warn!("Failed to record the completed repos: {:?}", err);
+                    }
+                }
+                Err(err) => failures.push((repo.name.clone(), err)),
+            };
+        }
+
+        // Every repo is done, and the steps after this modify the staged tests
+        // in place, so there is nothing left to resume
+        stop_if_interrupted(&specs_path)?;
+        if failures.is_empty() {
+            let _ = fs::remove_file(RUN_STATE);
+        }
+
+        // Try merging every proposal together, if requested
+        if let Some(branch) = &config.integration_branch {
+            let repos: Vec<&Repo> = successes
+                .iter()
+                .map(|(name, _)| config.find_repo(name).unwrap())
+                .collect();
+            match integration::build_integration(branch, &repos) {
+                Ok(result) => integration = Some(result),
+                Err(err) => warn!("Failed to build integration branch: {:?}", err),
+            }
+            stop_if_interrupted(&specs_path)?;
+        }
+
+        // Record where every output file came from
+        for (name, status) in &successes {
+            let repo = config.find_repo(name).unwrap();
+            let result = manifest
+                .add_repo(
+                    &output_dir,
+                    None,
+                    repo,
+                    &status.commit_final_hash,
+                    status.fallback_js,
+                )
+                .and_then(|_| {
+                    for VariantStatus {
+                        name: variant,
+                        commit,
+                        fallback_js,
+                        ..
+                    } in &status.variants
+                    {
+                        manifest.add_repo(
+                            &output_dir,
+                            Some(variant),
+                            repo,
+                            commit,
+                            *fallback_js,
+                        )?;
+                    }
+                    Ok(())
+                });
+            if let Err(err) = result {
+                failures.push(("manifest".to_owned(), err));
+            }
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Remove the tests that need features the engine doesn't support
+    if failures.is_empty() && (config.features.is_some() || !config.excluded_features.is_empty()) {
+        let _cd = change_dir(specs_dir);
+        match features::filter(
+            &output_dir,
+            &manifest,
+            config.features.as_deref(),
+            &config.excluded_features,
+        ) {
+            Ok(removed) => {
+                for test in &removed {
+                    debug!("Removed {}, which needs an unsupported feature", test);
+                }
+                info!(
+                    "Removed {} tests needing unsupported features",
+                    removed.len()
+                );
+            }
+            Err(err) => failures.push(("features".to_owned(), err)),
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Remove tests that are identical to what a parent already provides
+    if failures.is_empty() && config.omit_identical {
+        match omit::omit_identical(&config, &output_dir) {
+            Ok(omitted) => {
+                for (name, files) in &omitted {
+                    let mut manifest = String::new();
+                    for omission in files {
+                        manifest.push_str(&format!("{} = {}\n", omission.path, omission.ancestor));
+                    }
+                    write_string(reports_dir.join(format!("{}-omitted.txt", name)), &manifest)?;
+                }
+            }
+            Err(err) => failures.push(("omit_identical".to_owned(), err)),
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Look for tests duplicated across repos, optionally moving the identical
+    // ones into a shared directory
+    if failures.is_empty() && (config.report_duplicates || config.dedup_dir.is_some()) {
+        let result = duplicates::find_duplicates(&config, &output_dir).and_then(|duplicates| {
+            let mut report = String::from(
+                "# Duplicate tests\n\n| Test | Repos | Similarity |\n| --- | --- | --- |\n",
+            );
+            for duplicate in &duplicates {
+                report.push_str(&format!(
+                    "| {}/{} | {} | {} |\n",
+                    duplicate.kind,
+                    duplicate.path,
+                    duplicate.repos.join(", "),
+                    match duplicate.similarity {
+                        Some(x) => format!("{:.0}%", x * 100.0),
+                        None => "identical".to_owned(),
+                    }
+                ));
+            }
+            write_string(reports_dir.join("duplicates.md"), &report)?;
+
+            if let Some(dedup_dir) = &config.dedup_dir {
+                let moved = duplicates::deduplicate(&output_dir, &duplicates, dedup_dir)?;
+                manifest.deduplicate(&moved, dedup_dir);
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            failures.push(("duplicates".to_owned(), err));
+        }
+    }
+
+    // Write out the provenance of every file that made it into the output
+    if failures.is_empty() {
+        manifest.retain_existing(&output_dir);
+        let result = toml::to_string_pretty(&manifest)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| write_string(output_dir.join("MANIFEST.toml"), &x));
+        if let Err(err) = result {
+            failures.push(("manifest".to_owned(), err));
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Run the post-processing hook over the final tests
+    if failures.is_empty() {
+        if let Some(hook) = &config.post_process {
+            if let Err(err) = run_streamed("sh", &["-c", hook, "sh", output_dir.to_str().unwrap()])
+            {
+                failures.push(("post_process".to_owned(), err));
+            }
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Generate a Rust test crate for the final scripts, if requested
+    if failures.is_empty() && config.rust_harness {
+        if let Err(err) = rust_harness::write_crate(&config, &output_dir) {
+            failures.push(("rust_harness".to_owned(), err));
+        }
+    }
+
+    // Tag the tests with the features they need, if requested
+    if failures.is_empty() && config.feature_tags {
+        let _cd = change_dir(specs_dir);
+        if let Err(err) = features::write_features(&output_dir, &manifest) {
+            failures.push(("feature_tags".to_owned(), err));
+        }
+    }
+
+    // Describe each repo next to its tests
+    if failures.is_empty() {
+        if let Err(err) = write_readmes(&config, &output_dir, &successes) {
+            failures.push(("readmes".to_owned(), err));
+        }
+    }
+
+    // Carry each repo's license along with its tests
+    if failures.is_empty() {
+        if let Err(err) = write_licenses(&config, &output_dir, &successes) {
+            failures.push(("licenses".to_owned(), err));
+        }
+    }
+
+    // Stamp the output with how it was generated
+    if failures.is_empty() {
+        if let Err(err) = write_version(&output_dir, &successes) {
+            failures.push(("version".to_owned(), err));
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Move the tests into the configured layout, and point the manifest at
+    // where they ended up
+    if failures.is_empty() && config.layout() != layout::DEFAULT {
+        let result = layout::arrange(&config, config.layout(), &output_dir).and_then(|moved| {
+            manifest.relocate(&moved);
+            write_string(
+                output_dir.join("MANIFEST.toml"),
+                &toml::to_string_pretty(&manifest)?,
+            )
+        });
+        if let Err(err) = result {
+            failures.push(("layout".to_owned(), err));
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Checksum the final tests, so consumers can verify their copy
+    if failures.is_empty() {
+        if let Err(err) = write_checksums(&output_dir, config.jobs()) {
+            failures.push(("checksums".to_owned(), err));
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Run the generated tests to check they're usable, if requested
+    if let (true, Some(shell)) = (failures.is_empty(), &config.shell) {
+        let result = results::run_tests(&config, shell, &output_dir).and_then(|results| {
+            for (name, repo_results) in &results {
+                let passed = repo_results
+                    .values()
+                    .filter(|x| **x == results::Outcome::Pass)
+                    .count();
+                info!("{}: {}/{} tests passed", name, passed, repo_results.len());
+            }
+            write_string(
+                reports_dir.join("results.toml"),
+                &toml::to_string_pretty(&results)?,
+            )?;
+
+            // Compare against the known failures, then update them
+            if let Some(path) = &config.expectations {
+                let expected = results::load_expectations(Path::new(path))?;
+                let comparison = results::compare(&expected, &results);
+                let mut report = String::from("# Unexpected results\n\n");
+                for (name, test, outcome) in &comparison.regressed {
+                    warn!("{}/{}: unexpected {:?}", name, test, outcome);
+                    report.push_str(&format!("- {}/{}: {:?}\n", name, test, outcome));
+                }
+                report.push_str("\n# Changed expectations\n\n");
+                for (name, test, outcome) in &comparison.changed {
+                    info!("{}/{}: now {:?}", name, test, outcome);
+                    report.push_str(&format!("- {}/{}: {:?}\n", name, test, outcome));
+                }
+                write_string(reports_dir.join("expectations.md"), &report)?;
+
+                let expectations = results::expectations(&results);
+                write_string(path, &toml::to_string_pretty(&expectations)?)?;
+                if let Some(template) = &config.skip_list {
+                    write_string(
+                        reports_dir.join("skip-list.txt"),
+                        &results::skip_list(template, &expectations),
+                    )?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            failures.push(("shell".to_owned(), err));
+        }
+    }
+
+    stop_if_interrupted(&specs_path)?;
+
+    // Abort if we had a failure, keeping the previous tests
+    if !failures.is_empty() {
+        warn!("Failed.");
+        for (name, err) in &failures {
+            warn!("{}: (failure) {:?}", name, err);
+            annotate("error", &format!("{} failed", name), &format!("{:#}", err));
+        }
+        let names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+        bail!(
+            "failed to generate {}, keeping the previous tests",
+            names.join(", ")
+        );
+    }
+
+    // Summarize the changes to the previous tests, keeping a copy of them in
+    // '${output_dir}.prev' to compare against by hand
+    let changes = if final_dir.is_dir() {
+        let changes = diff::changes(&final_dir, &output_dir, &config)?;
+        keep_previous(
+            &final_dir,
+            &PathBuf::from(format!("{}.prev", final_dir.display())),
+        )?;
+        Some(changes)
+    } else {
+        None
+    };
+    sync_dirs(&output_dir, &final_dir, config.link_mode)?;
+
+    // Surface conflicts and broken builds in the checks UI
+    for (name, status) in &successes {
+        let mut outputs = vec![(
+            name.clone(),
+            &status.merged,
+            status.built,
+            &status.build_log,
+        )];
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            build_log,
+            ..
+        } in &status.variants
+        {
+            outputs.push((format!("{}/{}", name, variant), merged, *built, build_log));
+        }
+        for (name, merged, built, build_log) in outputs {
+            if let Merge::Conflicted(conflict) = merged {
+                annotate(
+                    "warning",
+                    &format!("{} conflicted", name),
+                    &format!(
+                        "failed to merge with {}: {}",
+                        conflict.parent,
+                        conflict.files.join(", ")
+                    ),
+                );
+            }
+            if !built {
+                annotate(
+                    "error",
+                    &format!("{} {}", name, build_state(built, build_log)),
+                    build_log.as_deref().unwrap_or("failed to build the tests"),
+                );
+            }
+        }
+    }
+
+    // Display successful results
+    info!("Done.");
+    let mut summary = String::from(
+        "# Summary\n\n| Repo | Commit | Status | Tests |\n| --- | --- | --- | --- |\n",
+    );
+    let mut notes = String::new();
+    let mut removed = String::new();
+    for (name, status) in &successes {
+        let repo = config.find_repo_mut(&name).unwrap();
+        lock.set_commit(
+            &name,
+            &status.commit_base_hash,
+            &status.commit_final_hash,
+            &status.commit_upstream_hash,
+        );
+
+        info!(
+            "{}: ({} {}) {}",
+            repo.name,
+            status.merged.name(),
+            build_state(status.built, &status.build_log),
+            status.commit_final_message.trim_end()
+        );
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            build_log,
+            ..
+        } in &status.variants
+        {
+            info!(
+                "{}/{}: ({} {})",
+                repo.name,
+                variant,
+                merged.name(),
+                build_state(*built, build_log),
+            );
+        }
+
+        summary.push_str(&format!(
+            "| {} | {} | {} | {} |\n",
+            repo.name,
+            status.commit_base_hash,
+            status.merged.name(),
+            build_state(status.built, &status.build_log),
+        ));
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            build_log,
+            ..
+        } in &status.variants
+        {
+            summary.push_str(&format!(
+                "| {}/{} | {} | {} | {} |\n",
+                repo.name,
+                variant,
+                status.commit_base_hash,
+                merged.name(),
+                build_state(*built, build_log),
+            ));
+        }
+        for note in &status.notes {
+            notes.push_str(&format!("- {}: {}\n", repo.name, note));
+        }
+        for test in &status.removed_tests {
+            removed.push_str(&match &test.renamed {
+                Some(renamed) => format!(
+                    "- {}: `{}` renamed to `{}`\n",
+                    repo.name, test.path, renamed
+                ),
+                None => format!("- {}: `{}` removed\n", repo.name, test.path),
+            });
+        }
+
+        // Write out the details of any conflicts for the proposal champion
+        if let Merge::Conflicted(conflict) = &status.merged {
+            write_string(
+                reports_dir.join(format!("{}-conflicts.md", repo.name)),
+                &conflict.to_markdown(&repo.name, &status.commit_base_hash),
+            )?;
+        }
+        for VariantStatus {
+            name: variant,
+            merged,
+            ..
+        } in &status.variants
+        {
+            if let Merge::Conflicted(conflict) = merged {
+                write_string(
+                    reports_dir.join(format!("{}-{}-conflicts.md", repo.name, variant)),
+                    &conflict.to_markdown(&repo.name, &status.commit_base_hash),
+                )?;
+            }
+        }
+    }
+    if !notes.is_empty() {
+        summary.push_str(&format!("\n## Notes\n\n{}", notes));
+    }
+    if !removed.is_empty() {
+        summary.push_str(&format!("\n## Removed or renamed tests\n\n{}", removed));
+    }
+    match &changes {
+        Some(changes) if changes.is_empty() => {
+            summary.push_str("\n## Changed tests\n\nNo changes.\n");
+        }
+        Some(changes) => {
+            summary.push_str(&format!("\n## Changed tests\n\n{}", changes.to_markdown(3)));
+        }
+        None => {}
+    }
+    write_string(reports_dir.join("summary.md"), &summary)?;
+    write_string(
+        reports_dir.join("summary.json"),
+        &summary_json(&successes, changes.as_ref()),
+    )?;
+
+    for event in notify::events(previous_version.as_ref(), &successes) {
+        for webhook in &config.webhooks {
+            if let Err(err) = notify::send(webhook, &event) {
+                warn!("Failed to send webhook: {:?}", err);
+            }
+        }
+    }
+
+    if let Some(integration) = &integration {
+        for (a, b, files) in &integration.conflicts {
+            warn!("{} conflicts with {}: {}", a, b, files.join(", "));
+        }
+        for name in &integration.unmerged {
+            warn!(
+                "{}: (conflicted) failed to merge into integration branch",
+                name
+            );
+        }
+    }
+
+    // Clean up the specs directory if it hasn't been for a while
+    if let Some(days) = config.gc_interval_days {
+        let _cd = change_dir(config.specs_dir());
+        if let Err(err) = gc_if_due(days, &lock) {
+            warn!("Failed to clean up the specs directory: {:?}", err);
+        }
+    }
+
+    // Commit the new lock file, dropping repos that were removed from the
+    // config
+    for name in lock.prune(&config) {
+        info!("Removed {} from the lock", name);
+    }
+    write_lock(&lock)?;
+
+    if config.commit_output {
+        stop_after_update_if_interrupted("the tests were updated but not committed")?;
+        commit_output(&final_dir, &previous_lock, &lock)?;
+        if let Some(pull_request) = &config.pull_request {
+            stop_after_update_if_interrupted("the tests were committed but not pushed")?;
+            update_pull_request(&final_dir, pull_request, &reports_dir.join("summary.md"))?;
+        }
+    }
+    if let Some(archive) = &config.archive {
+        info!("Archiving tests to {}", archive);
+        write_archive(&final_dir, Path::new(archive))?;
+    }
+    Ok(())
+}
+
+// Lock the specs directory, creating it if needed, so that overlapping runs
+// don't switch branches underneath each other. Fails if another run holds the
+// lock, unless waiting for it with `--wait` or `WGT_WAIT`.
+fn lock_specs(specs_dir: &str, options: &Options) -> Result<dir_lock::DirLock> {
+    fs::create_dir_all(specs_dir)?;
+    let wait = options.wait || env_bool("WGT_WAIT")?.unwrap_or(false);
+    dir_lock::acquire(Path::new(specs_dir), wait)
+}
+
+// Stop if generating was interrupted, after aborting any merge or patch left
+// in progress in the specs directory. The previous tests are untouched. While
+// repos are still being built, the ones completed so far are recorded so the
+// run can be resumed, and after that it has to start over.
+fn stop_if_interrupted(specs_dir: &Path) -> Result<()> {
+    if !interrupt::interrupted() {
+        return Ok(());
+    }
+    let specs_dir = specs_dir.to_str().unwrap();
+    for operation in &["merge", "rebase", "am", "cherry-pick"] {
+        let _ = run("git", &["-C", specs_dir, operation, "--abort"]);
+    }
+    let state = if Path::new(specs_dir).join(RUN_STATE).exists() {
+        "pass --resume to continue where this run stopped"
+    } else {
+        "keeping the previous tests"
+    };
+    Err(Interrupted(state.to_owned()).into())
+}
+
+// Stop if generating was interrupted once the previous tests were replaced,
+// describing what was left undone
+fn stop_after_update_if_interrupted(state: &str) -> Result<()> {
+    if interrupt::interrupted() {
+        return Err(Interrupted(state.to_owned()).into());
+    }
+    Ok(())
+}
+
+// Write a `README.md` into every output directory of each repo, describing
+// where its tests came from
+fn write_readmes(config: &Config, output_dir: &Path, successes: &[(String, Status)]) -> Result<()> {
+    for (name, status) in successes {
+        let repo = config.find_repo(name).unwrap();
+        let branch = &status.branch;
+        let directives = config.directives(repo);
+
+        let mut outputs = vec![(String::new(), &repo.parent, &status.merged, status.built)];
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            ..
+        } in &status.variants
+        {
+            let parent = &repo
+                .variants
+                .iter()
+                .find(|x| &x.name == variant)
+                .unwrap()
+                .parent;
+            outputs.push((format!("{}/", variant), parent, merged, *built));
+        }
+        for (prefix, parent, merged, built) in outputs {
+            let mut readme = format!("# {}\n\n", name);
+            readme.push_str(&format!("- Upstream: {} ({})\n", repo.url, branch));
+            readme.push_str(&format!("- Commit: {}\n", status.commit_base_hash));
+            readme.push_str(&format!(
+                "- Status: {}, {}\n",
+                merged.name(),
+                if built { "building" } else { "broken" }
+            ));
+            if !parent.is_empty() {
+                readme.push_str(&format!("- Parents: {}\n", parent.join(", ")));
+            }
+            if !directives.is_empty() {
+                readme.push_str(&format!(
+                    "\n## Directives\n\n```\n{}\n```\n",
+                    directives.trim_end()
+                ));
+            }
+
+            for kind in KINDS {
+                let dir = output_dir.join(&prefix).join(kind).join(repo.output_name());
+                if dir.exists() {
+                    write_string(dir.join("README.md"), &readme)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Copy the `LICENSE` of each repo into every output directory of it, and list
+// the repos with their commits and licenses in `ATTRIBUTION.md`
+fn write_licenses(
+    config: &Config,
+    output_dir: &Path,
+    successes: &[(String, Status)],
+) -> Result<()> {
+    let _cd = change_dir(config.specs_dir());
+    let mut attribution = String::from(
+        "# Attribution\n\nThe tests are generated from these repositories, each under \
+         the license copied next to its tests as `LICENSE`.\n\n\
+         | Repo | Upstream | Commit | License |\n| --- | --- | --- | --- |\n",
+    );
+    for (name, status) in successes {
+        let repo = config.find_repo(name).unwrap();
+        let mut outputs = vec![(name.clone(), String::new(), &status.commit_base_hash)];
+        for variant in &status.variants {
+            outputs.push((
+                format!("{}/{}", name, variant.name),
+                format!("{}/", variant.name),
+                &variant.commit,
+            ));
+        }
+        for (name, prefix, commit) in outputs {
+            let found = !run("git", &["ls-tree", "--name-only", commit, "LICENSE"])?.is_empty();
+            if found {
+                let license = run("git", &["show", &format!("{}:LICENSE", commit)])?;
+                for kind in KINDS {
+                    let dir = output_dir.join(&prefix).join(kind).join(repo.output_name());
+                    if dir.exists() {
+                        write_string(dir.join("LICENSE"), &format!("{}\n", license))?;
+                    }
+                }
+            } else {
+                warn!("{}: no LICENSE at {}", name, commit);
+            }
+            attribution.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                name,
+                repo.url,
+                commit,
+                if found { "LICENSE" } else { "none" }
+            ));
+        }
+    }
+    write_string(output_dir.join("ATTRIBUTION.md"), &attribution)
+}
+
+// The time the tests were generated, or `SOURCE_DATE_EPOCH` if set for a
+// reproducible build
+fn generated_date() -> Result<String> {
+    let time = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            let epoch: u64 = epoch
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid SOURCE_DATE_EPOCH `{}`", epoch))?;
+            UNIX_EPOCH + Duration::from_secs(epoch)
+        }
+        Err(_) => SystemTime::now(),
+    };
+    Ok(humantime::format_rfc3339_seconds(time).to_string())
+}
+
+// The summary as JSON, for tools consuming the reports
+fn summary_json(successes: &[(String, Status)], changes: Option<&diff::Changes>) -> String {
+    let repos: Vec<String> = successes
+        .iter()
+        .map(|(name, status)| {
+            let removed: Vec<String> = status
+                .removed_tests
+                .iter()
+                .filter(|x| x.renamed.is_none())
+                .map(|x| notify::json_string(&x.path))
+                .collect();
+            let renamed: Vec<String> = status
+                .removed_tests
+                .iter()
+                .filter_map(|x| {
+                    x.renamed.as_ref().map(|renamed| {
+                        format!(
+                            "{{\"from\":{},\"to\":{}}}",
+                            notify::json_string(&x.path),
+                            notify::json_string(renamed)
+                        )
+                    })
+                })
+                .collect();
+            let notes: Vec<String> = status
+                .notes
+                .iter()
+                .map(|x| notify::json_string(x))
+                .collect();
+            format!(
+                "{{\"name\":{},\"commit\":{},\"merged\":{},\"built\":{},\"notes\":[{}],\"removed\":[{}],\"renamed\":[{}]}}",
+                notify::json_string(name),
+                notify::json_string(&status.commit_base_hash),
+                notify::json_string(status.merged.name()),
+                status.built,
+                notes.join(","),
+                removed.join(","),
+                renamed.join(","),
+            )
+        })
+        .collect();
+    let changes = changes.map_or("null".to_owned(), |x| x.to_json());
+    format!(
+        "{{\"repos\":[{}],\"changes\":{}}}\n",
+        repos.join(","),
+        changes
+    )
+}
+
+fn write_version(output_dir: &Path, successes: &[(String, Status)]) -> Result<()> {
+    let mut repos = Vec::new();
+    for (name, status) in successes {
+        repos.push(VersionRepo {
+            name: name.clone(),
+            commit: status.commit_base_hash.clone(),
+            merged: status.merged.name().to_owned(),
+            built: status.built,
+        });
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            ..
+        } in &status.variants
+        {
+            repos.push(VersionRepo {
+                name: format!("{}/{}", name, variant),
+                commit: status.commit_base_hash.clone(),
+                merged: merged.name().to_owned(),
+                built: *built,
+            });
+        }
+    }
+
+    let version = Version {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        generated: generated_date()?,
+        repos,
+    };
+    write_string(
+        output_dir.join("VERSION.toml"),
+        &toml::to_string_pretty(&version)?,
+    )
+}
+
+// Write a `SHA256SUMS` file covering every other file in the output directory,
+// which can be checked with `sha256sum -c SHA256SUMS`.
+fn write_checksums(output_dir: &Path, jobs: usize) -> Result<()> {
+    let _cd = change_dir(output_dir.to_str().unwrap());
+    let _ = fs::remove_file("SHA256SUMS");
+
+    let paths: Vec<String> = find(".")
+        .iter()
+        .map(|x| x.strip_prefix(".").unwrap().to_str().unwrap().to_owned())
+        .collect();
+    if paths.is_empty() {
+        return write_string("SHA256SUMS", "");
+    }
+
+    let sums = parallel_map(jobs, &paths, |chunk| {
+        let mut args = vec!["--"];
+        args.extend(chunk.iter().map(|x| x.as_str()));
+        run("sha256sum", &args)
+    })?;
+    write_string("SHA256SUMS", &format!("{}\n", sums.join("\n")))
+}
+
+// Package the output directory, including its manifests, into a `.tar.gz`,
+// `.tgz` or `.zip` archive. Entries are sorted and have normalized owners so
+// that the same tests produce the same archive.
+fn write_archive(output_dir: &Path, archive: &Path) -> Result<()> {
+    let archive = env::current_dir()?.join(archive);
+    let archive_str = archive.to_str().unwrap();
+    let name = output_dir.file_name().unwrap().to_str().unwrap();
+    let _ = fs::remove_file(&archive);
+    fs::create_dir_all(archive.parent().unwrap())?;
+
+    let _cd = change_dir(output_dir.parent().unwrap().to_str().unwrap());
+    if archive_str.ends_with(".tar.gz") || archive_str.ends_with(".tgz") {
+        let mut args = vec!["--sort=name", "--owner=0", "--group=0", "--numeric-owner"];
+        let mtime = env::var("SOURCE_DATE_EPOCH").map(|x| format!("--mtime=@{}", x));
+        if let Ok(mtime) = &mtime {
+            args.push(mtime);
+        }
+        args.extend(["-czf", archive_str, name]);
+        run("tar", &args)?;
+    } else if archive_str.ends_with(".zip") {
+        let paths: Vec<String> = find(name)
+            .iter()
+            .map(|x| x.to_str().unwrap().to_owned())
+            .collect();
+        let mut args = vec!["-q", "-X", archive_str];
+        args.extend(paths.iter().map(|x| x.as_str()));
+        run("zip", &args)?;
+    } else {
+        bail!(
+            "unknown archive format for {}, expected .tar.gz, .tgz or .zip",
+            archive.display()
+        );
+    }
+    Ok(())
+}
+
+// Commit the output directory in the git checkout that contains it, listing
+// the repos whose commits changed in the message
+fn commit_output(output_dir: &Path, previous_lock: &Lock, lock: &Lock) -> Result<()> {
+    let dir = output_dir.to_str().unwrap();
+    run("git", &["-C", dir, "rev-parse", "--show-toplevel"])
+        .with_context(|| format!("{} is not in a git checkout", dir))?;
+    run("git", &["-C", dir, "add", "-A", "--", "."])?;
+    if run(
+        "git",
+        &["-C", dir, "diff", "--cached", "--name-only", "--", "."],
+    )?
+    .is_empty()
+    {
+        info!("No changes to commit in {}", dir);
+        return Ok(());
+    }
+
+    let mut message = String::from("Update generated wasm spec tests\n\n");
+    for repo in &lock.repos {
+        match previous_lock.find_commit(&repo.name) {
+            Some(previous) if previous == repo.commit => {}
+            Some(previous) => {
+                message.push_str(&format!("- {}: {}..{}\n", repo.name, previous, repo.commit))
+            }
+            None => message.push_str(&format!("- {}: (new) {}\n", repo.name, repo.commit)),
+        }
+    }
+
+    // Use the identity of the checkout rather than the fixed one used for
+    // merges, as this commit is kept
+    run(
+        "env",
+        &[
+            "-u",
+            "GIT_AUTHOR_NAME",
+            "-u",
+            "GIT_AUTHOR_EMAIL",
+            "-u",
+            "GIT_AUTHOR_DATE",
+            "-u",
+            "GIT_COMMITTER_NAME",
+            "-u",
+            "GIT_COMMITTER_EMAIL",
+            "-u",
+            "GIT_COMMITTER_DATE",
+            "git",
+            "-C",
+            dir,
+            "commit",
+            "-q",
+            "-m",
+            &message,
+            "--",
+            ".",
+        ],
+    )?;
+    info!("Committed the tests in {}", dir);
+    Ok(())
+}
+
+// Push the commit of the output directory to a branch, and open a pull request
+// for it with the summary as the body, or update the one already open
+fn update_pull_request(
+    output_dir: &Path,
+    pull_request: &PullRequest,
+    summary: &Path,
+) -> Result<()> {
+    let _cd = change_dir(output_dir.to_str().unwrap());
+    let refspec = format!("HEAD:refs/heads/{}", pull_request.branch);
+    run("git", &["push", "--force", pull_request.remote(), &refspec])?;
+
+    let mut repository = Vec::new();
+    if let Some(name) = &pull_request.repository {
+        repository.extend(["--repo", name.as_str()]);
+    }
+    let summary = summary.to_str().unwrap();
+
+    let mut args = vec![
+        "pr",
+        "list",
+        "--state",
+        "open",
+        "--head",
+        &pull_request.branch,
+    ];
+    args.extend(["--json", "number", "--jq", ".[0].number"]);
+    args.extend(&repository);
+    let number = run("gh", &args)?;
+    if number.is_empty() {
+        let mut args = vec!["pr", "create", "--head", &pull_request.branch];
+        args.extend(["--title", pull_request.title(), "--body-file", summary]);
+        if let Some(base) = &pull_request.base {
+            args.extend(["--base", base.as_str()]);
+        }
+        args.extend(&repository);
+        let url = run("gh", &args)?;
+        info!("Opened pull request {}", url);
+    } else {
+        let mut args = vec!["pr", "edit", &number, "--body-file", summary];
+        args.extend(&repository);
+        run("gh", &args)?;
+        info!("Updated pull request #{}", number);
+    }
+    Ok(())
+}
+
+// Make merge and patch commits with a fixed identity and date, so that the same
+// inputs always produce the same commits
+fn set_git_identity() {
+    let date = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => format!("@{} +0000", epoch),
+        Err(_) => "@0 +0000".to_owned(),
+    };
+    for (name, value) in &[
+        ("GIT_AUTHOR_NAME", "wasm-generate-testsuite"),
+        ("GIT_AUTHOR_EMAIL", "wasm-generate-testsuite@localhost"),
+        ("GIT_AUTHOR_DATE", &date),
+        ("GIT_COMMITTER_NAME", "wasm-generate-testsuite"),
+        ("GIT_COMMITTER_EMAIL", "wasm-generate-testsuite@localhost"),
+        ("GIT_COMMITTER_DATE", &date),
+    ] {
+        env::set_var(name, value);
+    }
+}
+
+// Order the repos so that every parent is processed before its children,
+// otherwise preserving the order from the config.
+fn sort_repos(config: &Config) -> Result<Vec<&Repo>> {
+    for repo in &config.repos {
+        for parent in &repo.parent {
+            if config.find_repo(parent).is_none() {
+                bail!("{}: unknown parent `{}`", repo.name, parent);
+            }
+        }
+    }
+
+    let mut sorted: Vec<&Repo> = Vec::new();
+    let mut remaining: Vec<&Repo> = config.repos.iter().collect();
+    while !remaining.is_empty() {
+        let next = remaining.iter().position(|repo| {
+            repo.parent
+                .iter()
+                .all(|parent| sorted.iter().any(|x| &x.name == parent))
+        });
+        match next {
+            Some(i) => sorted.push(remaining.remove(i)),
+            None => bail!(
+                "repos with cyclic parents: {}",
+                remaining
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+    Ok(sorted)
+}
+
+// Abort any merge, rebase, patch or bisect left in progress by a previous run
+// that was interrupted, as every later git command would fail. Must be run from
+// the specs directory.
+fn recover_specs() -> Result<()> {
+    let git_dir = Path::new(".git");
+    let states: &[(&str, &[&str])] = &[
+        ("rebase-apply/applying", &["am", "--abort"]),
+        ("rebase-apply", &["rebase", "--abort"]),
+        ("rebase-merge", &["rebase", "--abort"]),
+        ("MERGE_HEAD", &["merge", "--abort"]),
+        ("CHERRY_PICK_HEAD", &["cherry-pick", "--abort"]),
+        ("BISECT_LOG", &["bisect", "reset"]),
+    ];
+    for (file, abort) in states {
+        if git_dir.join(file).exists() {
+            warn!("Aborting `git {}` left in progress", abort[0]);
+            run("git", abort)?;
+        }
+    }
+    Ok(())
+}
+
+// Refuse to run over uncommitted changes to tracked files in the specs
+// directory, which would be lost to `git reset --hard`, unless told to stash
+// them. Must be run from the specs directory.
+fn stash_local_changes(discard: bool) -> Result<()> {
+    let changes = run("git", &["status", "--porcelain", "--untracked-files=no"])?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    if !discard {
+        bail!(
+            "the specs directory has local changes, which would be lost:\n{}\n\
+             commit them, or pass --discard-local-changes to stash them",
+            changes
+        );
+    }
+    run(
+        "git",
+        &[
+            "stash",
+            "push",
+            "-m",
+            "wasm-generate-testsuite: local changes",
+        ],
+    )?;
+    warn!("Stashed local changes in the specs directory, see `git stash list`");
+    Ok(())
+}
+
+// When resuming, the staged tests are kept and the statuses of the repos the
+// previous run completed are returned.
+fn clean_and_init_dirs(
+    specs_dir: &str,
+    output_dir: &Path,
+    reports_dir: &Path,
+    resume: bool,
+    discard: bool,
+) -> Result<BTreeMap<String, Status>> {
+    if !Path::new(specs_dir).join(".git").exists() {
+        fs::create_dir_all(specs_dir)?;
+        run("git", &["-C", specs_dir, "init"])?;
+    }
+    let resumed = {
+        let _cd = change_dir(specs_dir);
+        recover_specs()?;
+        stash_local_changes(discard)?;
+
+        let resumed = if resume {
+            load_run_state(output_dir)
+        } else {
+            None
+        };
+        if resumed.is_none() {
+            let _ = fs::remove_file(RUN_STATE);
+        }
+        resumed
+    };
+
+    if resumed.is_none() {
+        let _ = fs::remove_dir_all(output_dir);
+    }
+    let _ = fs::remove_dir_all(reports_dir);
+    Ok(resumed.unwrap_or_default())
+}
+
+// The statuses of the repos completed by the current run, for resuming it if
+// it fails. Relative to the specs directory.
+const RUN_STATE: &str = ".git/wgt-run.toml";
+
+fn write_run_state(successes: &[(String, Status)]) -> Result<()> {
+    let repos: BTreeMap<&str, &Status> = successes.iter().map(|(x, y)| (x.as_str(), y)).collect();
+    // Go through a `Value`, which orders the tables after the plain values
+    let state = toml::Value::try_from(&repos)?;
+    write_atomic(RUN_STATE, &toml::to_string(&state)?)
+}
+
+// Load the repos completed by the previous run, if it failed and its staged
+// tests are still there. Must be run from the specs directory.
+fn load_run_state(output_dir: &Path) -> Option<BTreeMap<String, Status>> {
+    let text = match fs::read_to_string(RUN_STATE) {
+        Ok(text) if output_dir.exists() => text,
+        _ => {
+            warn!("There is no failed run to resume, starting over");
+            return None;
+        }
+    };
+    match toml::from_str(&text) {
+        Ok(resumed) => Some(resumed),
+        Err(err) => {
+            warn!("Failed to load the state of the previous run: {:?}", err);
+            None
+        }
+    }
+}
+
+// Update `dir` to match `new_dir`, only rewriting the files that changed so
+// their modification times are kept, then remove `new_dir`
+fn sync_dirs(new_dir: &Path, dir: &Path, link_mode: LinkMode) -> Result<()> {
+    if !dir.exists() {
+        fs::rename(new_dir, dir)
+            .with_context(|| format!("failed to move {}", new_dir.display()))?;
+        return Ok(());
+    }
+
+    for path in find(new_dir.to_str().unwrap()) {
+        let relative = path.strip_prefix(new_dir).unwrap();
+        let old_path = dir.join(relative);
+        if fs::read(&old_path).is_ok_and(|x| fs::read(&path).is_ok_and(|y| x == y)) {
+            continue;
+        }
+        fs::create_dir_all(old_path.parent().unwrap())?;
+        link_mode
+            .link(&path, &old_path)
+            .with_context(|| format!("failed to copy {}", path.display()))?;
+    }
+    for path in find(dir.to_str().unwrap()) {
+        if !new_dir.join(path.strip_prefix(dir).unwrap()).exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+    remove_empty_dirs(dir)?;
+    fs::remove_dir_all(new_dir)?;
+    Ok(())
+}
+
+// Replace `prev_dir` with a copy of `dir`. The files are copied rather than
+// linked, as syncing may write over the files in `dir`.
+fn keep_previous(dir: &Path, prev_dir: &Path) -> Result<()> {
+    if prev_dir.exists() {
+        fs::remove_dir_all(prev_dir)
+            .with_context(|| format!("failed to remove {}", prev_dir.display()))?;
+    }
+    for path in find(dir.to_str().unwrap()) {
+        let prev_path = prev_dir.join(path.strip_prefix(dir).unwrap());
+        fs::create_dir_all(prev_path.parent().unwrap())?;
+        fs::copy(&path, &prev_path)
+            .with_context(|| format!("failed to copy {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Build a repo that has been fetched, from its upstream branch
+fn build_repo(
+    repo: &Repo,
+    branch_upstream: &str,
+    config: &Config,
+    lock: &Lock,
+    output_dir: &Path,
+) -> Result<Status> {
+    let branch_base = repo.name.clone();
+    let mut notes = Vec::new();
+
+    // Note if upstream was force-pushed since the lock was updated, as the
+    // locked commit may no longer be on the branch
+    let commit_upstream_hash = run("git", &["rev-parse", branch_upstream])?;
+    if let Some(previous) = lock.find_upstream(&repo.name) {
+        if previous != commit_upstream_hash
+            && run(
+                "git",
+                &[
+                    "merge-base",
+                    "--is-ancestor",
+                    previous,
+                    &commit_upstream_hash,
+                ],
+            )
+            .is_err()
+        {
+            let note = format!(
+                "upstream was force-pushed from {} to {}",
+                previous, commit_upstream_hash
+            );
+            warn!("{}: {}", repo.name, note);
+            notes.push(note);
+        }
+    }
+
+    // Checkout the pinned commit, if any, and get the absolute commit hash
+    let base_treeish = lock.find_commit(&repo.name).unwrap_or(branch_upstream);
+    run("git", &["checkout", &branch_base])?;
+    run("git", &["reset", base_treeish, "--hard"])?;
+    let mut commit_base_hash = run("git", &["log", "--pretty=%h", "-n", "1"])?
+        .trim()
+        .to_owned();
+
+    let mut build_log = None;
+    let (mut merged, mut built, mut fallback_js) = build_tests(
+        repo,
+        config,
+        &repo.parent,
+        &commit_base_hash,
+        output_dir,
+        &mut notes,
+        &mut build_log,
+    )?;
+
+    // If upstream is broken, walk back to an older commit that builds
+    if !built && repo.fallback_commits > 0 {
+        let broken_hash = commit_base_hash.clone();
+        let mut fallback = None;
+        for candidate in fallback_candidates(repo, lock, &broken_hash)? {
+            info!("Falling back to {}", candidate);
+            clean_repo_output(repo, output_dir);
+            run("git", &["reset", &candidate, "--hard"])?;
+            let result = build_tests(
+                repo,
+                config,
+                &repo.parent,
+                &candidate,
+                output_dir,
+                &mut notes,
+                &mut build_log,
+            )?;
+            if result.1 {
+                fallback = Some((candidate, result));
+                break;
+            }
+        }
+
+        match fallback {
+            Some((candidate, result)) => {
+                notes.push(format!(
+                    "{} failed to build, fell back to {}",
+                    broken_hash, candidate
+                ));
+                commit_base_hash = candidate;
+                merged = result.0;
+                built = result.1;
+                fallback_js = result.2;
+            }
+            None => {
+                notes.push(format!(
+                    "{} failed to build, and no fallback commit built",
+                    broken_hash
+                ));
+                clean_repo_output(repo, output_dir);
+                run("git", &["reset", &broken_hash, "--hard"])?;
+                let result = build_tests(
+                    repo,
+                    config,
+                    &repo.parent,
+                    &broken_hash,
+                    output_dir,
+                    &mut notes,
+                    &mut build_log,
+                )?;
+                merged = result.0;
+                built = result.1;
+                fallback_js = result.2;
+            }
+        }
+        warn!("{}: {}", repo.name, notes.last().unwrap());
+    }
+
+    // Keep the generated tests around for any children to compare against
+    cache_generated(repo, built)?;
+
+    // Get the final commit message we ended up on
+    let commit_final_message = run("git", &["log", "--oneline", "-n", "1"])?;
+
+    // Build each variant against its own parent revisions, then restore the
+    // default merged state for any children
+    let commit_final_hash = run("git", &["rev-parse", "HEAD"])?;
+    let mut variants = Vec::new();
+    for variant in &repo.variants {
+        info!("Building variant {}", variant.name);
+        run("git", &["reset", &commit_base_hash, "--hard"])?;
+        let mut variant_build_log = None;
+        let (merged, built, fallback_js) = build_tests(
+            repo,
+            config,
+            &variant.parent,
+            &commit_base_hash,
+            &output_dir.join(&variant.name),
+            &mut notes,
+            &mut variant_build_log,
+        )?;
+        let commit_variant_hash = run("git", &["rev-parse", "HEAD"])?;
+        variants.push(VariantStatus {
+            name: variant.name.clone(),
+            merged,
+            built,
+            commit: commit_variant_hash,
+            build_log: variant_build_log,
+            fallback_js,
+        });
+    }
+    run("git", &["reset", &commit_final_hash, "--hard"])?;
+
+    let branch = branch_upstream[repo.name.len() + 1..].to_owned();
+    Ok(Status {
+        branch,
+        commit_final_message,
+        commit_base_hash,
+        commit_final_hash,
+        commit_upstream_hash,
+        merged,
+        built,
+        build_log,
+        fallback_js,
+        variants,
+        notes,
+        removed_tests: Vec::new(),
+    })
+}
+
+// The `.wast` tests in `test/` that were removed or renamed between two commits
+fn removed_tests(previous: &str, commit: &str) -> Result<Vec<RemovedTest>> {
+    let diff = run(
+        "git",
+        &[
+            "diff",
+            "--name-status",
+            "-M",
+            "--diff-filter=DR",
+            previous,
+            commit,
+            "--",
+            "test/",
+        ],
+    )?;
+    let mut removed = Vec::new();
+    for line in diff.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [_, path] | [_, path, _] if path.ends_with(".wast") => removed.push(RemovedTest {
+                path: path.to_string(),
+                renamed: fields.get(2).map(|x| x.to_string()),
+            }),
+            _ => {}
+        }
+    }
+    Ok(removed)
+}
+
+// The upstream branch of a repo, which is the default branch of its remote
+// unless configured. Falls back to `master` if the remote can't be queried.
+fn remote_branch(repo: &Repo) -> String {
+    if let Some(branch) = &repo.branch {
+        return branch.clone();
+    }
+    let output = match run_remote(repo, &["ls-remote", "--symref", repo.remote_url(), "HEAD"]) {
+        Ok(output) => output,
+        Err(err) => {
+            warn!(
+                "{}: failed to find the default branch: {:?}",
+                repo.name, err
+            );
+            return "master".to_owned();
+        }
+    };
+    // The symref is listed as `ref: refs/heads/${branch}\tHEAD`
+    output
+        .lines()
+        .find_map(|x| x.strip_prefix("ref: refs/heads/"))
+        .and_then(|x| x.split('\t').next())
+        .unwrap_or("master")
+        .to_owned()
+}
+
+// Add the remote and branches for a repo if they don't exist, and fetch the
+// latest changes. Returns the upstream branch.
+fn fetch_repo(repo: &Repo, lock: &Lock) -> Result<String> {
+    add_remote(repo)?;
+    let remote_branch = fetch_remote(repo, lock.find_commit(&repo.name))?;
+    track_branch(repo, &remote_branch)
+}
+
+// Fetch the latest changes of every repo up front, as the fetches of separate
+// remotes can run concurrently. Returns the upstream branch of each repo, or
+// why it couldn't be fetched.
+fn fetch_repos(
+    repos: &[&Repo],
+    lock: &Lock,
+    jobs: usize,
+) -> Result<HashMap<String, Result<String>>> {
+    // Adding remotes writes to the git config, so is done one at a time
+    let mut fetched = HashMap::new();
+    let mut added = Vec::new();
+    for repo in repos {
+        match add_remote(repo) {
+            Ok(()) => added.push(*repo),
+            Err(err) => {
+                fetched.insert(repo.name.clone(), Err(err));
+            }
+        }
+    }
+
+    // Fetches that deepen or shorten the history take a lock on the list of
+    // shallow commits, so are done one at a time after the others
+    let shallow = run("git", &["rev-parse", "--is-shallow-repository"])? == "true";
+    let (serial, parallel): (Vec<&Repo>, Vec<&Repo>) = added
+        .into_iter()
+        .partition(|x| changes_shallow(x, lock.find_commit(&x.name), shallow));
+
+    let fetch_one = |repo: &Repo| {
+        info!("Fetching {}", repo.name);
+        let previous = runner::set_label(Some(&repo.name));
+        let remote_branch = fetch_remote(repo, lock.find_commit(&repo.name));
+        runner::set_label(previous.as_deref());
+        remote_branch
+    };
+    let remote_branches = parallel_map(jobs, &parallel, |chunk| {
+        Ok(chunk.iter().map(|repo| fetch_one(repo)).collect::<Vec<_>>())
+    })?;
+    let remote_branches = remote_branches
+        .into_iter()
+        .flatten()
+        .chain(serial.iter().map(|repo| fetch_one(repo)));
+    for (repo, remote_branch) in parallel.iter().chain(&serial).zip(remote_branches) {
+        let branch_upstream = remote_branch.and_then(|x| track_branch(repo, &x));
+        fetched.insert(repo.name.clone(), branch_upstream);
+    }
+    Ok(fetched)
+}
+
+// Whether fetching a repo may change which commits of the specs directory are
+// shallow: if it's fetched with a depth or in full, or its pinned commit is
+// missing from a shallow history
+fn changes_shallow(repo: &Repo, pinned: Option<&str>, shallow: bool) -> bool {
+    let missing = |pinned| {
+        let commit = format!("{}^{{commit}}", pinned);
+        run("git", &["cat-file", "-e", &commit]).is_err()
+    };
+    repo.fetch_depth.is_some() || repo.full_history || (shallow && pinned.is_some_and(missing))
+}
+
+// Fetch from the remote of a repo. Other repos are fetched at the same time, so
+// this doesn't write `FETCH_HEAD` or start an automatic gc, which would race
+// with them.
+fn fetch(repo: &Repo, args: &[&str]) -> Result<String> {
+    let mut fetch_args = vec!["-c", "gc.auto=0", "fetch", "--no-write-fetch-head"];
+    fetch_args.extend(args);
+    run_remote(repo, &fetch_args)
+}
+
+// Add the remote of a repo if it doesn't exist, or update its URL
+fn add_remote(repo: &Repo) -> Result<()> {
+    let remote_name = &repo.name;
+    let remote_url = repo.remote_url();
+    let remotes = run("git", &["remote"])?;
+    if !remotes.lines().any(|x| x == repo.name) {
+        run("git", &["remote", "add", remote_name, remote_url])?;
+    } else if run("git", &["remote", "get-url", remote_name])? != remote_url {
+        run("git", &["remote", "set-url", remote_name, remote_url])?;
+    }
+    Ok(())
+}
+
+// Fetch the latest changes of a repo's branch from its remote, along with the
+// pinned commit and any extra commits to cherry-pick. Returns the remote
+// branch. This only updates refs of the repo, so may run concurrently for other
+// repos.
+fn fetch_remote(repo: &Repo, pinned: Option<&str>) -> Result<String> {
+    let remote_name = &repo.name;
+    let remote_branch = remote_branch(repo);
+
+    // A shallow history from fetching with a depth is completed if the repo
+    // needs all of it
+    let unshallow =
+        repo.full_history && run("git", &["rev-parse", "--is-shallow-repository"])? == "true";
+
+    // Skip fetching if the remote branch hasn't moved since the last fetch
+    let tracking_ref = format!("refs/remotes/{}/{}", repo.name, remote_branch);
+    let fetched = run("git", &["rev-parse", "--verify", "-q", &tracking_ref]).ok();
+    if fetched.is_some() && fetched == remote_commit(repo, &remote_branch) && !unshallow {
+        info!("{} is unchanged, not fetching", repo.name);
+    } else {
+        // Only fetch the branch, rather than every branch and tag of the remote
+        // unless configured
+        let refspec = format!("+refs/heads/{}:{}", remote_branch, tracking_ref);
+        let depth = repo.fetch_depth.map(|x| format!("--depth={}", x));
+        let mut args = vec![if repo.fetch_tags {
+            "--tags"
+        } else {
+            "--no-tags"
+        }];
+        args.extend(depth.as_deref());
+        if unshallow {
+            args.push("--unshallow");
+        }
+        args.extend(&[remote_name.as_str(), &refspec]);
+        fetch(repo, &args)?;
+    }
+
+    if let Some(pinned) = pinned {
+        fetch_pinned(repo, pinned, &remote_branch)?;
+    }
+
+    // Fetch any extra commits to cherry-pick into local refs, as they may not
+    // be reachable from any branch (e.g. `pull/123/head`)
+    for (i, cherry_pick) in repo.cherry_picks.iter().enumerate() {
+        let local_ref = cherry_pick_ref(repo, i);
+        let commit = format!("{}^{{commit}}", cherry_pick);
+        if run("git", &["cat-file", "-e", &commit]).is_ok() {
+            run("git", &["update-ref", &local_ref, &commit])?;
+        } else {
+            fetch(
+                repo,
+                &[
+                    "--no-tags",
+                    remote_name,
+                    &format!("+{}:{}", cherry_pick, local_ref),
+                ],
+            )
+            .with_context(|| format!("failed to fetch cherry-pick `{}`", cherry_pick))?;
+        }
+    }
+
+    Ok(remote_branch)
+}
+
+// Make sure the pinned commit of a repo is there to reset to. It's usually on
+// the branch, but may be hidden by a shallow fetch, or only be on another
+// branch if the branch was force-pushed. As a last resort it's fetched by its
+// hash, which only works for full hashes.
+fn fetch_pinned(repo: &Repo, pinned: &str, remote_branch: &str) -> Result<()> {
+    let remote_name = &repo.name;
+    let commit = format!("{}^{{commit}}", pinned);
+    let exists = || run("git", &["cat-file", "-e", &commit]).is_ok();
+    if exists() {
+        return Ok(());
+    }
+
+    if run("git", &["rev-parse", "--is-shallow-repository"])? == "true" {
+        info!("{} is not in the shallow history, deepening it", pinned);
+        fetch(repo, &["--no-tags", "--unshallow", remote_name])?;
+        if exists() {
+            return Ok(());
+        }
+    }
+
+    info!(
+        "{} is not on {}, fetching every branch of {}",
+        pinned, remote_branch, repo.name
+    );
+    fetch(repo, &["--no-tags", remote_name])?;
+    if exists() {
+        return Ok(());
+    }
+
+    let is_full_hash = pinned.len() == 40 && pinned.chars().all(|x| x.is_ascii_hexdigit());
+    if is_full_hash {
+        info!("Fetching {} from {} by its hash", pinned, repo.name);
+        let _ = fetch(repo, &["--no-tags", remote_name, pinned]);
+        if exists() {
+            return Ok(());
+        }
+    }
+    bail!(
+        "pinned commit {} is no longer reachable from {}, it may have been force-pushed \
+         away and garbage collected upstream. Pin {} to another commit in \
+         config-lock.toml, or remove its entry to use the latest commit.",
+        pinned,
+        repo.remote_url(),
+        repo.name
+    )
+}
+
+// The commit at the head of a remote branch, or `None` if it can't be found
+fn remote_commit(repo: &Repo, branch: &str) -> Option<String> {
+    let head_ref = format!("refs/heads/{}", branch);
+    let output = run_remote(repo, &["ls-remote", repo.remote_url(), &head_ref]).ok()?;
+    output.lines().find_map(|x| {
+        let (commit, name) = x.split_once('\t')?;
+        (name == head_ref).then(|| commit.to_owned())
+    })
+}
+
+// Create the branch of a repo if it doesn't exist, tracking the remote branch.
+// Returns the upstream branch.
+fn track_branch(repo: &Repo, remote_branch: &str) -> Result<String> {
+    let branch_upstream = format!("{}/{}", repo.name, remote_branch);
+    let branch_base = repo.name.clone();
+
+    // Initialize our branch if it doesn't exist
+    let branch_ref = format!("refs/heads/{}", branch_base);
+    if run("git", &["show-ref", "--verify", "--quiet", &branch_ref]).is_err() {
+        run("git", &["branch", &branch_base, &branch_upstream])?;
+    }
+
+    // Set the upstream to the correct branch
+    run(
+        "git",
+        &[
+            "branch",
+            &branch_base,
+            "--set-upstream-to",
+            &branch_upstream,
+        ],
+    )?;
+
+    Ok(branch_upstream)
+}
+
+// The commits to try when the base commit is broken: the previously locked
+// commit, followed by the first-parent ancestors of the base.
+fn fallback_candidates(repo: &Repo, lock: &Lock, commit_base_hash: &str) -> Result<Vec<String>> {
+    let mut candidates = Vec::new();
+    if let Some(locked) = lock.find_commit(&repo.name) {
+        let locked = run("git", &["log", "--pretty=%h", "-n", "1", locked])?;
+        if locked != commit_base_hash {
+            candidates.push(locked);
+        }
+    }
+
+    let count = format!("{}", repo.fallback_commits + 1);
+    let ancestors = run(
+        "git",
+        &[
+            "log",
+            "--pretty=%h",
+            "--first-parent",
+            "-n",
+            &count,
+            commit_base_hash,
+        ],
+    )?;
+    for ancestor in ancestors.lines().skip(1) {
+        if !candidates.iter().any(|x| x == ancestor) {
+            candidates.push(ancestor.to_owned());
+        }
+    }
+    Ok(candidates)
+}
+
+// Remove any tests that were copied for a repo
+fn clean_repo_output(repo: &Repo, output_dir: &Path) {
+    for kind in KINDS {
+        let _ = fs::remove_dir_all(output_dir.join(kind).join(repo.output_name()));
+    }
+}
+
+// Merge with the parents, build the tests and copy the selected ones into the
+// output directory. Returns how it merged, whether it built, and whether the
+// `.js` tests were converted by the fallback after the build script failed.
+fn build_tests(
+    repo: &Repo,
+    config: &Config,
+    parents: &[String],
+    commit_base_hash: &str,
+    output_dir: &Path,
+    notes: &mut Vec<String>,
+    build_log: &mut Option<String>,
+) -> Result<(Merge, bool, bool)> {
+    // Apply extra commits and our local patches, which then become the base
+    // for merging
+    apply_cherry_picks(repo)?;
+    apply_patches(repo, config)?;
+    let commit_patched_hash = run("git", &["log", "--pretty=%h", "-n", "1"])?;
+    if commit_patched_hash != commit_base_hash {
+        info!("Patched {} to {}", commit_base_hash, commit_patched_hash);
+    }
+
+    // Try to merge with parent repos, if specified
+    let merged = try_merge_parent(repo, parents, &commit_patched_hash)?;
+
+    // Try to build the test suite on this commit. This may fail due to merging
+    // with a parent repo, in which case we will try again in an unmerged state.
+    let mut built = false;
+    let mut fallback_js = false;
+    match build_interpreter(repo)
+        .context(INTERPRETER_FAILED)
+        .and_then(|_| run_hook(repo, &repo.pre_build))
+        .and_then(|_| try_build_tests(repo, true))
+        .and_then(|fallback| {
+            fallback_js = fallback;
+            run_hook(repo, &repo.post_build)
+        }) {
+        Ok(()) => {
+            built = true;
+            *build_log = None;
+            if fallback_js {
+                notes.push(format!(
+                    "`{}` failed, the JS tests were converted by the fallback generator",
+                    repo.build_script.as_deref().unwrap()
+                ));
+            }
+        }
+        Err(err) => {
+            warn!("Failed to build tests: {:?}", err);
+            *build_log = Some(format!("{:?}", err));
+        }
+    };
+    // if try_build_tests().is_err() {
+    //     if repo.parent.is_some() {
+    //         warn!(
+    //             "Failed to build interpreter. Retrying on unmerged commit ({})",
+    //             &commit_base_hash
+    //         );
+    //         run("git", &["reset", &commit_base_hash, "--hard"])?;
+    //         built = try_build_tests().is_ok();
+    //     } else {
+    //         built = false;
+    //     }
+    // }
+    // if !built {
+    //     warn!("Failed to build interpreter, Won't emit js/html");
+    // }
+
+    // Compute the source files that changed, and use that to filter the files
+    // we copy over. We can't compare the generated tests, because for a
+    // generated WPT we need to copy both the .js and .html even if only
+    // one of those is different from the master.
+    let tests_changed = find_tests_changed(repo, config, parents)?;
+    info!("Changed tests: {:#?}", tests_changed);
+
+    // Include the changed tests, specified files, and `harness/` directory
+    let mut included_files = Vec::new();
+    included_files.extend_from_slice(&tests_changed);
+    included_files.extend_from_slice(&config.included_tests);
+    included_files.extend_from_slice(&repo.included_tests);
+    included_files.push("harness/".to_owned());
+
+    // Exclude files specified from the config and repo
+    let mut excluded_files = Vec::new();
+    excluded_files.extend_from_slice(&config.excluded_tests);
+    excluded_files.extend_from_slice(&repo.excluded_tests);
+
+    // Generate a regex set of the files to include or exclude
+    let include = RegexSetBuilder::new(&included_files)
+        .build()
+        .context("invalid included_tests pattern")?;
+    let mut exclude = RegexSetBuilder::new(&excluded_files)
+        .build()
+        .context("invalid excluded_tests pattern")?;
+
+    // Exclude the scripts containing anything that can't be run, along with
+    // the tests generated from them
+    let content_patterns: Vec<&String> = config
+        .excluded_content
+        .iter()
+        .chain(&repo.excluded_content)
+        .collect();
+    if !content_patterns.is_empty() {
+        let content = RegexSetBuilder::new(content_patterns)
+            .build()
+            .context("invalid excluded_content pattern")?;
+        let mut excluded = Vec::new();
+        for path in find("test/core") {
+            let relative = path.strip_prefix("test/core").unwrap().to_str().unwrap();
+            if path.extension() != Some(OsStr::new("wast"))
+                || in_excluded_dir(repo, config, relative)
+                || !include.is_match(relative)
+                || exclude.is_match(relative)
+            {
+                continue;
+            }
+
+            if content.is_match(&fs::read_to_string(&path)?) {
+                info!("Excluding {} by its content", path.display());
+                excluded.extend(script_exclusions(relative));
+            }
+        }
+        if !excluded.is_empty() {
+            excluded_files.extend(excluded);
+            exclude = RegexSetBuilder::new(&excluded_files).build()?;
+        }
+    }
+
+    // Check that the scripts we would copy parse, optionally excluding the
+    // ones that don't
+    if config.validate_wast || config.exclude_invalid_wast {
+        let mut invalid = Vec::new();
+        for path in find("test/core") {
+            let relative = path.strip_prefix("test/core").unwrap().to_str().unwrap();
+            if path.extension() != Some(OsStr::new("wast"))
+                || in_excluded_dir(repo, config, relative)
+                || !include.is_match(relative)
+                || exclude.is_match(relative)
+            {
+                continue;
+            }
+
+            let source = fs::read_to_string(&path)?;
+            if let Err(err) = wast2js::validate(&path, &source) {
+                warn!("Invalid test {}: {}", path.display(), err);
+                let note = format!("invalid test {}", path.display());
+                if !notes.contains(&note) {
+                    notes.push(note);
+                }
+                invalid.extend(script_exclusions(relative));
+            }
+        }
+        if config.exclude_invalid_wast && !invalid.is_empty() {
+            excluded_files.extend(invalid);
+            exclude = RegexSetBuilder::new(&excluded_files).build()?;
+        }
+    }
+
+    // Copy over all the desired test-suites
+    if !repo.skip_wast {
+        copy_tests(
+            repo,
+            config,
+            "test/core",
+            output_dir,
+            "wast",
+            &include,
+            &exclude,
+        )?;
+    }
+    if let (false, Some(wast2json)) = (repo.skip_json, &config.wast2json) {
+        convert_json_tests(
+            repo, config, wast2json, output_dir, &include, &exclude, notes,
+        )?;
+    }
+    if built && !repo.skip_js {
+        // Select the generated tests by comparing them against the parents'
+        // generated tests, if requested and possible
+        let generated_changed = if config.diff_generated && !parents.is_empty() {
+            find_generated_changed(parents)?
+        } else {
+            None
+        };
+        let include = match generated_changed {
+            Some(mut included_files) => {
+                info!("Changed generated tests: {:#?}", included_files);
+                included_files.extend_from_slice(&config.included_tests);
+                included_files.extend_from_slice(&repo.included_tests);
+                included_files.push("harness/".to_owned());
+                RegexSetBuilder::new(&included_files).build()?
+            }
+            None => include,
+        };
+
+        // Check that the scripts we would copy parse, excluding the ones that
+        // don't in case a bad merge broke the generator
+        let mut exclude = exclude.clone();
+        if let Some(check_js) = &config.check_js {
+            let mut invalid = Vec::new();
+            for path in find("js") {
+                let relative = path.strip_prefix("js").unwrap().to_str().unwrap();
+                if in_excluded_dir(repo, config, relative)
+                    || !include.is_match(relative)
+                    || exclude.is_match(relative)
+                {
+                    continue;
+                }
+
+                if let Err(err) = run("sh", &["-c", check_js, "sh", path.to_str().unwrap()]) {
+                    warn!("Invalid generated test {}: {:?}", path.display(), err);
+                    let note = format!("invalid generated test {}", path.display());
+                    if !notes.contains(&note) {
+                        notes.push(note);
+                    }
+                    invalid.push(format!("^{}$", regex::escape(relative)));
+                }
+            }
+            if !invalid.is_empty() {
+                let mut excluded_files = excluded_files.clone();
+                excluded_files.extend(invalid);
+                exclude = RegexSetBuilder::new(&excluded_files).build()?;
+            }
+        }
+
+        copy_tests(repo, config, "js", output_dir, "js", &include, &exclude)?;
+        copy_directives(repo, config, parents, commit_base_hash, output_dir)?;
+    }
+    if !repo.skip_js_api && Path::new("test/js-api").exists() {
+        copy_js_api_tests(repo, config, parents, output_dir, &exclude)?;
+    }
+    mark_long_timeouts(repo, config, output_dir)?;
+    if config.any_js {
+        convert_any_js(repo, output_dir)?;
+    }
+    if config.layout() == layout::WPT {
+        write_dir_metadata(repo, config, parents, commit_base_hash, output_dir)?;
+    }
+    copy_extra_dirs(repo, config, output_dir, notes)?;
+
+    Ok((merged, built, fallback_js))
+}
+
+// Copy the extra directories of a repo verbatim into `extra/${repo}`, keeping
+// their paths within the repo
+fn copy_extra_dirs(
+    repo: &Repo,
+    config: &Config,
+    output_dir: &Path,
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    let walk = Walk::from_config(config)?;
+    for dir in &repo.extra_dirs {
+        let dir = dir.trim_end_matches('/');
+        if !Path::new(dir).is_dir() {
+            let note = format!("extra directory {} doesn't exist", dir);
+            warn!("{}", note);
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+            continue;
+        }
+
+        for path in walk.find(dir) {
+            let out_path = output_dir
+                .join("extra")
+                .join(repo.output_name())
+                .join(&path);
+            fs::create_dir_all(out_path.parent().unwrap())?;
+            config
+                .link_mode
+                .link(&path, &out_path)
+                .with_context(|| format!("copying {} to {}", path.display(), out_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+// Copy the JS API tests, which are used as-is. Unlike the core tests, these
+// are selected by their path, and helper scripts are always included.
+fn copy_js_api_tests(
+    repo: &Repo,
+    config: &Config,
+    parents: &[String],
+    output_dir: &Path,
+    exclude: &RegexSet,
+) -> Result<()> {
+    let dir = "test/js-api";
+
+    let mut included_files = Vec::new();
+    for path in find_files_changed(repo, parents, dir)? {
+        if let Ok(path) = path.strip_prefix(dir) {
+            included_files.push(format!("^{}$", regex::escape(path.to_str().unwrap())));
+        }
+    }
+    for path in find(dir) {
+        let path = path.strip_prefix(dir).unwrap().to_str().unwrap();
+        if !path.ends_with(".any.js") {
+            included_files.push(format!("^{}$", regex::escape(path)));
+        }
+    }
+    included_files.extend_from_slice(&config.included_tests);
+    included_files.extend_from_slice(&repo.included_tests);
+
+    let include = RegexSetBuilder::new(&included_files).build()?;
+    copy_tests(repo, config, dir, output_dir, "js-api", &include, exclude)
+}
+
+// Patterns excluding a `.wast` script, relative to `test/core`, along with its
+// `.json` test and its generated `.js` test. The generators name the `.js` test
+// after the script, either at the same path or by its file name alone.
+fn script_exclusions(relative: &str) -> Vec<String> {
+    let name = Path::new(relative).file_name().unwrap().to_str().unwrap();
+    vec![
+        format!("^{}(\\.js)?$", regex::escape(relative)),
+        format!("^{}\\.js$", regex::escape(name)),
+    ]
+}
+
+// Convert the selected scripts into the JSON and binary modules used by
+// wabt-style spec test runners, with an external `wast2json`
+fn convert_json_tests(
+    repo: &Repo,
+    config: &Config,
+    wast2json: &str,
+    output_dir: &Path,
+    include: &RegexSet,
+    exclude: &RegexSet,
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    let command = format!("{} \"$1\" -o \"$2\"", wast2json);
+    for path in find("test/core") {
+        let relative = path.strip_prefix("test/core").unwrap();
+        let relative_str = relative.to_str().unwrap();
+        if path.extension() != Some(OsStr::new("wast"))
+            || in_excluded_dir(repo, config, relative_str)
+            || !include.is_match(relative_str)
+            || exclude.is_match(relative_str)
+        {
+            continue;
+        }
+
+        let out_path = output_dir
+            .join("json")
+            .join(repo.output_name())
+            .join(relative.with_extension("json"));
+        fs::create_dir_all(out_path.parent().unwrap())?;
+        let args = [
+            "-c",
+            &command,
+            "sh",
+            path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+        ];
+        if let Err(err) = run("sh", &args) {
+            warn!("Failed to convert {} to JSON: {:?}", path.display(), err);
+            let note = format!("failed to convert {} to JSON", path.display());
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cherry_pick_ref(repo: &Repo, index: usize) -> String {
+    format!("refs/cherry-picks/{}/{}", repo.name, index)
+}
+
+fn apply_cherry_picks(repo: &Repo) -> Result<()> {
+    for (i, cherry_pick) in repo.cherry_picks.iter().enumerate() {
+        run("git", &["cherry-pick", &cherry_pick_ref(repo, i)])
+            .inspect_err(|_| {
+                let _ = run("git", &["cherry-pick", "--abort"]);
+            })
+            .with_context(|| {
+                format!(
+                    "cherry-pick `{}` no longer applies to {}",
+                    cherry_pick, repo.name
+                )
+            })?;
+    }
+    Ok(())
+}
+
+fn apply_patches(repo: &Repo, config: &Config) -> Result<()> {
+    for pattern in &repo.patches {
+        let paths = glob(&config.root.join(pattern))?;
+        if paths.is_empty() {
+            bail!("no patches match `{}`", pattern);
+        }
+
+        for path in paths {
+            let path_str = path.to_str().unwrap();
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+
+            // Patches from `git format-patch` carry their own commit message
+            let applied = if text.starts_with("From ") {
+                run("git", &["am", "-q", path_str]).inspect_err(|_| {
+                    let _ = run("git", &["am", "--abort"]);
+                })
+            } else {
+                let message = format!("Apply {}", path.file_name().unwrap().to_str().unwrap());
+                run("git", &["apply", "--index", path_str])
+                    .and_then(|_| run("git", &["commit", "-q", "-m", &message]))
+            };
+            applied.with_context(|| {
+                format!(
+                    "patch {} no longer applies to {}",
+                    path.display(),
+                    repo.name
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn try_merge_parent(repo: &Repo, parents: &[String], commit_base_hash: &str) -> Result<Merge> {
+    if parents.is_empty() {
+        return Ok(Merge::Standalone);
+    }
+
+    // Try to merge with each parent branch in order, so that the result
+    // reflects the full stack of features.
+    for parent in parents {
+        let conflict = match repo.integration {
+            Strategy::Merge => {
+                let message = format!("Merging {}:{}with {}", repo.name, commit_base_hash, parent);
+                try_merge(parent, &message, &repo.auto_resolve())?
+            }
+            Strategy::Rebase => try_rebase(parent)?,
+        };
+        if let Some(conflict) = conflict {
+            // Reset to master if we failed
+            warn!(
+                "Failed to integrate {} with {}, falling back to {}.",
+                repo.name, parent, &commit_base_hash
+            );
+            run("git", &["reset", commit_base_hash, "--hard"])?;
+            return Ok(Merge::Conflicted(conflict));
+        }
+    }
+    Ok(match repo.integration {
+        Strategy::Merge => Merge::Merged,
+        Strategy::Rebase => Merge::Rebased,
+    })
+}
+
+// Merge a branch into the current one, returning the conflict if it failed. A
+// failed merge is aborted.
+fn try_merge(
+    branch: &str,
+    message: &str,
+    auto_resolve: &[(&str, Side)],
+) -> Result<Option<Conflict>> {
+    if run("git", &["merge", "-q", branch, "-m", message]).is_ok() {
+        return Ok(None);
+    }
+
+    // Resolve conflicts in paths we don't care about, and give up if any
+    // others remain.
+    let conflicted = run("git", &["diff", "--name-only", "--diff-filter=U"])?;
+    let mut resolved = !conflicted.is_empty();
+    for file in conflicted.lines() {
+        let side = match auto_resolve
+            .iter()
+            .find(|(path, _)| path_contains(path, file))
+        {
+            Some((_, side)) => side,
+            None => {
+                resolved = false;
+                break;
+            }
+        };
+        let side_flag = match side {
+            Side::Ours => "--ours",
+            Side::Theirs => "--theirs",
+        };
+        if run("git", &["checkout", side_flag, "--", file]).is_err()
+            || run("git", &["add", "--", file]).is_err()
+        {
+            resolved = false;
+            break;
+        }
+    }
+    if resolved && run("git", &["-c", "core.editor=true", "merge", "--continue"]).is_ok() {
+        return Ok(None);
+    }
+
+    let conflict = Conflict::capture(branch)?;
+    run("git", &["merge", "--abort"])?;
+    Ok(Some(conflict))
+}
+
+// Whether `file` is `path` or inside of the directory `path`
+fn path_contains(path: &str, file: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    file == path || (file.starts_with(path) && file[path.len()..].starts_with('/'))
+}
+
+// Rebase the current branch onto another, returning the conflict if it failed.
+// A failed rebase is aborted.
+fn try_rebase(branch: &str) -> Result<Option<Conflict>> {
+    if run("git", &["rebase", "-q", branch]).is_ok() {
+        return Ok(None);
+    }
+
+    let conflict = Conflict::capture(branch)?;
+    run("git", &["rebase", "--abort"])?;
+    Ok(Some(conflict))
+}
+
+// Run a shell hook in the worktree, passing the repo name as `$1`
+fn run_hook(repo: &Repo, hook: &Option<String>) -> Result<()> {
+    if let Some(hook) = hook {
+        run_streamed("sh", &["-c", hook, "sh", &repo.name])?;
+    }
+    Ok(())
+}
+
+// The reference interpreters built so far, by the hash of their source tree
+const INTERPRETER_CACHE: &str = ".git/wgt-interpreter";
+
+// The start of the build log when the interpreter failed to build, rather than
+// the tests
+const INTERPRETER_FAILED: &str = "failed to build the reference interpreter";
+
+// Build the reference interpreter of the worktree, if the repo has a command
+// for it. Its outputs are cached by the hash of `interpreter/`, and restored
+// instead of building again when that hasn't changed.
+fn build_interpreter(repo: &Repo) -> Result<()> {
+    let build = match &repo.interpreter_build {
+        Some(build) => build,
+        None => return Ok(()),
+    };
+    let tree = run("git", &["rev-parse", "HEAD:interpreter"])?;
+    let cache_dir = Path::new(INTERPRETER_CACHE).join(&tree);
+    let outputs = &repo.interpreter_outputs;
+    if !outputs.is_empty() && outputs.iter().all(|x| cache_dir.join(x).exists()) {
+        info!("Reusing the interpreter built from {}", tree);
+        for output in outputs {
+            if let Some(parent) = Path::new(output).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(cache_dir.join(output), output)?;
+        }
+        return Ok(());
+    }
+
+    run_streamed("sh", &["-c", build, "sh", &repo.name])?;
+    let _ = fs::remove_dir_all(&cache_dir);
+    for output in outputs {
+        let cache_path = cache_dir.join(output);
+        fs::create_dir_all(cache_path.parent().unwrap())?;
+        fs::copy(output, &cache_path)
+            .with_context(|| format!("the interpreter build didn't write {}", output))?;
+    }
+    Ok(())
+}
+
+// Whether a repo built, and if not whether it was the interpreter that failed
+fn build_state(built: bool, build_log: &Option<String>) -> &'static str {
+    match build_log {
+        _ if built => "building",
+        Some(log) if log.starts_with(INTERPRETER_FAILED) => "broken interpreter",
+        _ => "broken",
+    }
+}
+
+// Generate the `.js` tests into `js/`. If the repo's build script fails and
+// `fallback` is set, the scripts are converted as if it had none, returning
+// whether that happened.
+fn try_build_tests(repo: &Repo, fallback: bool) -> Result<bool> {
+    let _ = fs::remove_dir_all("./js");
+    fs::create_dir("./js")?;
+
+    // The repo's own generator writes the `.js` tests and their harness
+    let mut fell_back = false;
+    if let Some(script) = &repo.build_script {
+        let js_dir = env::current_dir()?.join("js");
+        let python = repo.python.as_deref().unwrap_or("python3");
+        match run_streamed(python, &[script, "--js", js_dir.to_str().unwrap()]) {
+            Ok(_) => return Ok(false),
+            Err(err) if fallback => {
+                warn!(
+                    "{} failed, converting the scripts instead: {:?}",
+                    script, err
+                );
+                fs::remove_dir_all("./js")?;
+                fs::create_dir("./js")?;
+                fell_back = true;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let paths = find("./test/core/");
+    for path in paths {
+        if path.extension() != Some(OsStr::new("wast")) {
+            continue;
+        }
+
+        let source = std::fs::read_to_string(&path)?;
+        let script = wast2js::convert(&path, &source)?;
+
+        std::fs::write(
+            Path::new("./js").join(&path.with_extension("wast.js").file_name().unwrap()),
+            &script,
+        )?;
+    }
+
+    fs::create_dir("./js/harness")?;
+    write_string("./js/harness/harness.js", &wast2js::harness())?;
+
+    Ok(fell_back)
+}
+
+// Whether a test is below one of the excluded directories of the config or
+// repo, given its path relative to the tests
+fn in_excluded_dir(repo: &Repo, config: &Config, path: &str) -> bool {
+    config
+        .excluded_dirs
+        .iter()
+        .chain(&repo.excluded_dirs)
+        .any(|x| path_contains(x, path))
+}
+
+fn copy_tests(
+    repo: &Repo,
+    config: &Config,
+    src_dir: &str,
+    dst_dir: &Path,
+    test_name: &str,
+    include: &RegexSet,
+    exclude: &RegexSet,
+) -> Result<()> {
+    // The destinations written so far, compared case-insensitively as the
+    // tests may end up on a case-insensitive filesystem
+    let mut written: HashMap<String, PathBuf> = HashMap::new();
+
+    // Select the files to copy first, then copy them in parallel
+    let mut copies = Vec::new();
+    for path in Walk::from_config(config)?.find(src_dir) {
+        let stripped_path = path.strip_prefix(src_dir).unwrap();
+        let stripped_path_str = stripped_path.to_str().unwrap();
+
+        // Whole directories are excluded before matching any patterns
+        if in_excluded_dir(repo, config, stripped_path_str)
+            || !include.is_match(stripped_path_str)
+            || exclude.is_match(stripped_path_str)
+        {
+            continue;
+        }
+
+        let out_path = dst_dir
+            .join(test_name)
+            .join(repo.output_name())
+            .join(&stripped_path);
+        if let Some(earlier) =
+            written.insert(out_path.to_str().unwrap().to_lowercase(), path.clone())
+        {
+            bail!(
+                "{} and {} both copy to {}",
+                earlier.display(),
+                path.display(),
+                out_path.display()
+            );
+        }
+        if out_path.exists() {
+            bail!(
+                "copying {} would overwrite {}, written earlier in this run",
+                path.display(),
+                out_path.display()
+            );
+        }
+
+        copies.push((path, out_path));
+    }
+
+    parallel_map(config.jobs(), &copies, |chunk| {
+        for (path, out_path) in chunk {
+            let out_dir = out_path.parent().unwrap();
+            let _ = fs::create_dir_all(out_dir);
+            config
+                .link_mode
+                .link(path, out_path)
+                .with_context(|| format!("copying {} to {}", path.display(), out_path.display()))?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// The values of the placeholders in directives, which may refer to where the
+// tests came from
+fn directive_variables(
+    repo: &Repo,
+    parents: &[String],
+    commit_base_hash: &str,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut parent_commits = Vec::new();
+    for parent in parents {
+        parent_commits.push(run("git", &["log", "--pretty=%h", "-n", "1", parent])?);
+    }
+    Ok(vec![
+        ("repo", repo.name.clone()),
+        ("commit", commit_base_hash.to_owned()),
+        ("parent", parents.join(",")),
+        ("parent_commit", parent_commits.join(",")),
+    ])
+}
+
+fn expand_directive(directive: &str, variables: &[(&str, String)]) -> String {
+    let mut directive = directive.to_owned();
+    for (name, value) in variables {
+        directive = directive.replace(&format!("{{{}}}", name), value);
+    }
+    directive
+}
+
+fn copy_directives(
+    repo: &Repo,
+    config: &Config,
+    parents: &[String],
+    commit_base_hash: &str,
+    output_dir: &Path,
+) -> Result<()> {
+    let variables = directive_variables(repo, parents, commit_base_hash)?;
+    let expand = |directive: &str| expand_directive(directive, &variables);
+
+    // Write directives files
+    let harness_directives = format!(
+        "{}{}",
+        config.harness_directive.as_deref().unwrap_or(""),
+        repo.harness_directive.as_deref().unwrap_or("")
+    );
+    if config.harness_directive.is_some() || repo.harness_directive.is_some() {
+        let directives_path = output_dir
+            .join("js")
+            .join(repo.output_name())
+            .join("harness/directives.txt");
+        write_string(&directives_path, &expand(&harness_directives))?;
+    }
+    let directives = config.directives(repo);
+    if !directives.is_empty() {
+        let directives_path = output_dir
+            .join("js")
+            .join(repo.output_name())
+            .join("directives.txt");
+        write_string(&directives_path, &expand(&directives))?;
+    }
+
+    // Prepend the directives for single tests as comments
+    let test_directives: Vec<(Regex, &str)> = config
+        .test_directives
+        .iter()
+        .chain(&repo.test_directives)
+        .map(|(pattern, directive)| Ok((Regex::new(pattern)?, directive.as_str())))
+        .collect::<Result<_>>()?;
+    if test_directives.is_empty() {
+        return Ok(());
+    }
+    let dir = output_dir.join("js").join(repo.output_name());
+    for path in find(dir.to_str().unwrap()) {
+        let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+        if relative.starts_with("harness/") || DIRECTIVES_FILES.contains(&relative) {
+            continue;
+        }
+
+        let mut header = String::new();
+        for (pattern, directive) in &test_directives {
+            if pattern.is_match(relative) {
+                for line in expand(directive).lines() {
+                    header.push_str(&format!("// {}\n", line));
+                }
+            }
+        }
+        if !header.is_empty() {
+            // Replace the file rather than writing through a hard link
+            let script = fs::read_to_string(&path)?;
+            fs::remove_file(&path)?;
+            write_string(&path, &format!("{}{}", header, script))?;
+        }
+    }
+    Ok(())
+}
+
+// Give the `.js` and JS API tests matching `long_timeout_tests` the long WPT
+// timeout, with a `META` comment at the start of the file
+fn mark_long_timeouts(repo: &Repo, config: &Config, output_dir: &Path) -> Result<()> {
+    let patterns: Vec<&String> = config
+        .long_timeout_tests
+        .iter()
+        .chain(&repo.long_timeout_tests)
+        .collect();
+    if patterns.is_empty() {
+        return Ok(());
+    }
+    let long_timeout = RegexSetBuilder::new(patterns)
+        .build()
+        .context("invalid long_timeout_tests pattern")?;
+
+    for kind in &["js", "js-api"] {
+        let dir = output_dir.join(kind).join(repo.output_name());
+        if !dir.exists() {
+            continue;
+        }
+        for path in find(dir.to_str().unwrap()) {
+            let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+            if relative.starts_with("harness/")
+                || DIRECTIVES_FILES.contains(&relative)
+                || !relative.ends_with(".js")
+                || !long_timeout.is_match(relative)
+            {
+                continue;
+            }
+
+            // Replace the file rather than writing through a hard link
+            let script = fs::read_to_string(&path)?;
+            fs::remove_file(&path)?;
+            write_string(&path, &format!("// META: timeout=long\n{}", script))?;
+        }
+    }
+    Ok(())
+}
+
+// Convert the `.js` tests into WPT `.any.js` tests, which run in both a window
+// and a worker, loading the harness with `META` comments. Tests using the DOM
+// only run in a window, so they're kept as they are.
+fn convert_any_js(repo: &Repo, output_dir: &Path) -> Result<()> {
+    let dir = output_dir.join("js").join(repo.output_name());
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut header = String::from("// META: global=window,dedicatedworker\n");
+    let harness_dir = dir.join("harness");
+    let mut harness: Vec<PathBuf> = find(harness_dir.to_str().unwrap())
+        .into_iter()
+        .filter(|x| x.extension() == Some(OsStr::new("js")))
+        .collect();
+    harness.sort();
+    for path in harness {
+        let relative = path.strip_prefix(&harness_dir).unwrap().to_str().unwrap();
+        header.push_str(&format!(
+            "// META: script=/wasm/resources/{}/{}\n",
+            repo.output_name(),
+            relative
+        ));
+    }
+
+    let window_only = Regex::new(r"\b(document|window)\b").unwrap();
+    for path in find(dir.to_str().unwrap()) {
+        let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+        let stem = match relative.strip_suffix(".js") {
+            Some(stem) if !relative.starts_with("harness/") && !stem.ends_with(".any") => stem,
+            _ => continue,
+        };
+        let script = fs::read_to_string(&path)?;
+        if window_only.is_match(&script) {
+            continue;
+        }
+
+        // Any `META` comments must stay at the start
+        let meta_end = script
+            .lines()
+            .take_while(|x| x.starts_with("// META:"))
+            .map(|x| x.len() + 1)
+            .sum::<usize>()
+            .min(script.len());
+        let (meta, rest) = script.split_at(meta_end);
+        fs::remove_file(&path)?;
+        write_string(
+            dir.join(format!("{}.any.js", stem)),
+            &format!("{}{}{}", meta, header, rest),
+        )?;
+    }
+    Ok(())
+}
+
+// Write the directive lists as the prefs of a wptrunner `__dir__.ini` in the
+// directories of the `.js` and JS API tests, which become `wasm/core/${repo}`
+// and `wasm/jsapi/${repo}` in the WPT layout
+fn write_dir_metadata(
+    repo: &Repo,
+    config: &Config,
+    parents: &[String],
+    commit_base_hash: &str,
+    output_dir: &Path,
+) -> Result<()> {
+    let prefs = config.directive_list(repo);
+    if prefs.is_empty() {
+        return Ok(());
+    }
+    let variables = directive_variables(repo, parents, commit_base_hash)?;
+    let metadata = format!(
+        "prefs: [{}]\n",
+        expand_directive(&prefs.join(", "), &variables)
+    );
+    for kind in &["js", "js-api"] {
+        let dir = output_dir.join(kind).join(repo.output_name());
+        if dir.exists() {
+            write_string(dir.join("__dir__.ini"), &metadata)?;
+        }
+    }
+    Ok(())
+}
+
+// The time of the last garbage collection of the specs directory
+const GC_STAMP: &str = ".git/wgt-last-gc";
+
+// Expire the reflogs, which keep the commits of old pins and fetches alive, and
+// then repack and prune everything that's unreachable. The locked commits are
+// kept, even if upstream was force-pushed. Must be run from the specs
+// directory.
+fn gc_specs(lock: &Lock) -> Result<()> {
+    info!("Cleaning up the specs directory");
+    for repo in &lock.repos {
+        let reference = format!("refs/locked/{}", repo.name);
+        if run("git", &["update-ref", &reference, &repo.commit]).is_err() {
+            warn!("{}: locked commit {} is missing", repo.name, repo.commit);
+        }
+    }
+    run(
+        "git",
+        &[
+            "reflog",
+            "expire",
+            "--expire=now",
+            "--expire-unreachable=now",
+            "--all",
+        ],
+    )?;
+    run("git", &["gc", "--prune=now"])?;
+
+    // Drop the interpreters built from trees that are gone
+    if let Ok(entries) = fs::read_dir(INTERPRETER_CACHE) {
+        for entry in entries {
+            let path = entry?.path();
+            let tree = path.file_name().unwrap().to_str().unwrap();
+            if run("git", &["cat-file", "-e", tree]).is_err() {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    write_string(GC_STAMP, &now.to_string())
+}
+
+// Garbage collect the specs directory if it hasn't been within `days`
+fn gc_if_due(days: u64, lock: &Lock) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let last: u64 = fs::read_to_string(GC_STAMP)
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(0);
+    if now.saturating_sub(last) < days * 24 * 60 * 60 {
+        return Ok(());
+    }
+    gc_specs(lock)
+}
+
+// The generated tests of each repo are cached in the git directory, where they
+// are unaffected by checkouts
+fn generated_cache_dir(name: &str) -> PathBuf {
+    Path::new(".git/wgt-generated").join(name)
+}
+
+fn cache_generated(repo: &Repo, built: bool) -> Result<()> {
+    let cache_dir = generated_cache_dir(&repo.name);
+    let _ = fs::remove_dir_all(&cache_dir);
+    if !built {
+        return Ok(());
+    }
+
+    for path in find("js") {
+        let cache_path = cache_dir.join(path.strip_prefix("js").unwrap());
+        fs::create_dir_all(cache_path.parent().unwrap())?;
+        fs::copy(&path, &cache_path)?;
+    }
+    Ok(())
+}
+
+// Find the generated tests that differ from the generated tests of every
+// parent, as patterns. Returns `None` if a parent has no generated tests.
+fn find_generated_changed(parents: &[String]) -> Result<Option<Vec<String>>> {
+    let cache_dirs: Vec<PathBuf> = parents.iter().map(|x| generated_cache_dir(x)).collect();
+    if cache_dirs.iter().any(|x| !x.exists()) {
+        return Ok(None);
+    }
+
+    let mut changed = Vec::new();
+    for path in find("js") {
+        let relative = path.strip_prefix("js").unwrap();
+        let contents = fs::read(&path)?;
+        if cache_dirs
+            .iter()
+            .all(|x| fs::read(x.join(relative)).map_or(true, |x| x != contents))
+        {
+            changed.push(format!("^{}$", regex::escape(relative.to_str().unwrap())));
+        }
+    }
+    Ok(Some(changed))
+}
+
+fn find_tests_changed(repo: &Repo, config: &Config, parents: &[String]) -> Result<Vec<String>> {
+    // Tests are `.wast` files, unless other extensions are configured
+    let mut extensions: Vec<&str> = config
+        .test_extensions
+        .iter()
+        .chain(&repo.test_extensions)
+        .map(|x| x.as_str())
+        .collect();
+    if extensions.is_empty() {
+        extensions.push("wast");
+    }
+
+    // Matches `(input $name? "file")` in a script, which reads another file
+    let input = Regex::new(r#"\(input\s+(?:\$\S+\s+)?"([^"]+)"\s*\)"#).unwrap();
+
+    let mut tests_changed = Vec::new();
+    for path in find_files_changed(repo, parents, "test/core")? {
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+        if !extensions.contains(&extension) {
+            continue;
+        }
+
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        tests_changed.push(name);
+
+        // Include any assets that the test reads
+        if extension == "wast" {
+            if let Ok(text) = fs::read_to_string(&path) {
+                for captures in input.captures_iter(&text) {
+                    tests_changed.push(captures[1].to_owned());
+                }
+            }
+        }
+    }
+    Ok(tests_changed)
+}
+
+// Find the files in a directory that differ from the parents, or all of them
+// if there are no parents
+fn find_files_changed(repo: &Repo, parents: &[String], dir: &str) -> Result<Vec<PathBuf>> {
+    Ok(if parents.is_empty() {
+        find(dir)
+    } else {
+        // A file is only changed if it differs from every parent, otherwise
+        // the parent that matches it will already provide it.
+        let mut files_changed: Option<Vec<PathBuf>> = None;
+        for parent in parents {
+            let diff: Vec<PathBuf> = run("git", &["diff", "--name-only", &repo.name, parent, dir])?
+                .lines()
+                .map(PathBuf::from)
+                .collect();
+            files_changed = Some(match files_changed {
+                Some(files) => files.into_iter().filter(|x| diff.contains(x)).collect(),
+                None => diff,
+            });
+        }
+        files_changed.unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::runner::{set_runner, CommandRunner as _, MockRunner, ProcessRunner};
+
+    // Run commands with a fresh mock runner on this thread
+    fn mock() -> Arc<MockRunner> {
+        let mock = Arc::new(MockRunner::new());
+        set_runner(mock.clone());
+        mock
+    }
+
+    fn repo(name: &str) -> Repo {
+        Repo {
+            name: name.to_owned(),
+            url: format!("https://github.com/WebAssembly/{}", name),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn remote_branch_from_symref() {
+        let mock = mock();
+        mock.respond(
+            "git ls-remote --symref",
+            "ref: refs/heads/main\tHEAD\n0123456789abcdef\tHEAD",
+        );
+        assert_eq!(remote_branch(&repo("gc")), "main");
+        assert_eq!(
+            mock.calls(),
+            ["git ls-remote --symref https://github.com/WebAssembly/gc HEAD"]
+        );
+    }
+
+    #[test]
+    fn remote_branch_falls_back_to_master() {
+        let mock = mock();
+        mock.fail("git ls-remote", "could not resolve host");
+        assert_eq!(remote_branch(&repo("gc")), "master");
+    }
+
+    #[test]
+    fn remote_branch_configured() {
+        let mock = mock();
+        let mut repo = repo("gc");
+        repo.branch = Some("upstream-rebuild".to_owned());
+        assert_eq!(remote_branch(&repo), "upstream-rebuild");
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn fetch_skipped_if_unchanged() {
+        let mock = mock();
+        mock.respond("git rev-parse --verify -q refs/remotes/gc/main", "0123abcd");
+        mock.respond("git ls-remote https://", "0123abcd\trefs/heads/main");
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        assert_eq!(fetch_remote(&repo, None).unwrap(), "main");
+        assert!(!mock.calls().iter().any(|x| x.contains(" fetch ")));
+    }
+
+    #[test]
+    fn fetch_if_changed() {
+        let mock = mock();
+        mock.respond("git rev-parse --verify -q refs/remotes/gc/main", "0123abcd");
+        mock.respond("git ls-remote https://", "4567cdef\trefs/heads/main");
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        fetch_remote(&repo, None).unwrap();
+        assert!(mock
+            .calls()
+            .contains(&"git -c gc.auto=0 fetch --no-write-fetch-head --no-tags gc +refs/heads/main:refs/remotes/gc/main".to_owned()));
+    }
+
+    #[test]
+    fn fetch_with_depth_and_tags() {
+        let mock = mock();
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        repo.fetch_depth = Some(50);
+        repo.fetch_tags = true;
+        fetch_remote(&repo, None).unwrap();
+        assert!(mock.calls().contains(
+            &"git -c gc.auto=0 fetch --no-write-fetch-head --tags --depth=50 gc +refs/heads/main:refs/remotes/gc/main".to_owned()
+        ));
+    }
+
+    #[test]
+    fn fetch_full_history_of_shallow_repo() {
+        let mock = mock();
+        mock.respond("git rev-parse --is-shallow-repository", "true");
+        mock.respond("git rev-parse --verify -q refs/remotes/gc/main", "0123abcd");
+        mock.respond("git ls-remote https://", "0123abcd\trefs/heads/main");
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        repo.full_history = true;
+        fetch_remote(&repo, None).unwrap();
+        assert!(mock.calls().contains(
+            &"git -c gc.auto=0 fetch --no-write-fetch-head --no-tags --unshallow gc +refs/heads/main:refs/remotes/gc/main".to_owned()
+        ));
+    }
+
+    #[test]
+    fn fetch_every_branch_for_missing_pin() {
+        let mock = mock();
+        mock.fail("git cat-file -e 89ab", "missing");
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        let err = fetch_remote(&repo, Some("89ab")).unwrap_err();
+        assert!(mock
+            .calls()
+            .contains(&"git -c gc.auto=0 fetch --no-write-fetch-head --no-tags gc".to_owned()));
+        assert!(err.to_string().contains("no longer reachable"), "{}", err);
+    }
+
+    #[test]
+    fn deepen_and_fetch_missing_pin_by_hash() {
+        let mock = mock();
+        let pinned = "0123456789abcdef0123456789abcdef01234567";
+        mock.fail(&format!("git cat-file -e {}", pinned), "missing");
+        mock.respond("git rev-parse --is-shallow-repository", "true");
+        let mut repo = repo("gc");
+        repo.branch = Some("main".to_owned());
+        assert!(fetch_remote(&repo, Some(pinned)).is_err());
+        let calls = mock.calls();
+        assert!(calls.contains(
+            &"git -c gc.auto=0 fetch --no-write-fetch-head --no-tags --unshallow gc".to_owned()
+        ));
+        assert!(calls.contains(&format!(
+            "git -c gc.auto=0 fetch --no-write-fetch-head --no-tags gc {}",
+            pinned
+        )));
+    }
+
+    #[test]
+    fn run_remote_with_proxy() {
+        let mock = mock();
+        let mut repo = repo("gc");
+        repo.proxy = Some("http://proxy:3128".to_owned());
+        run_remote(&repo, &["fetch", "gc"]).unwrap();
+        assert_eq!(
+            mock.calls(),
+            ["git -c http.proxy=http://proxy:3128 fetch gc"]
+        );
+    }
+
+    #[test]
+    fn local_changes_refused() {
+        let mock = mock();
+        mock.respond("git status", " M test/core/binary.wast");
+        assert!(stash_local_changes(false).is_err());
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[test]
+    fn local_changes_stashed() {
+        let mock = mock();
+        mock.respond("git status", " M test/core/binary.wast");
+        stash_local_changes(true).unwrap();
+        assert!(mock.calls()[1].starts_with("git stash push"));
+    }
+
+    #[test]
+    fn options_override_config() {
+        let mut config = Config {
+            net_jobs: Some(4),
+            repos: vec![repo("spec")],
+            ..Default::default()
+        };
+        let options = Options {
+            link_mode: Some(LinkMode::Hardlink),
+            net_jobs: NonZeroUsize::new(2),
+            only: Some(vec!["wast".to_owned()]),
+            ..Default::default()
+        };
+        apply_options(&mut config, &options).unwrap();
+        assert_eq!(config.link_mode, LinkMode::Hardlink);
+        assert_eq!(config.net_jobs, Some(2));
+        assert!(!config.repos[0].skip_wast);
+        assert!(config.repos[0].skip_js);
+
+        let options = Options {
+            only: Some(vec!["wat".to_owned()]),
+            ..Default::default()
+        };
+        let err = apply_options(&mut config, &options).unwrap_err();
+        assert!(err.to_string().contains("invalid kind in --only: `wat`"));
+    }
+
+    #[test]
+    fn pending_without_specs_dir() {
+        let _mock = mock();
+        let config = Config {
+            specs_dir: Some("/dev/null/specs".to_owned()),
+            repos: vec![repo("spec")],
+            ..Default::default()
+        };
+        let err = upstream::pending(&config, &empty_lock()).err().unwrap();
+        assert_eq!(err.to_string(), "failed to create /dev/null/specs");
+    }
+
+    #[test]
+    fn integration_conflicts_from_merge_tree() {
+        let mock = mock();
+        mock.exit(
+            "git merge-tree",
+            1,
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904\ntest/core/a.wast\ntest/core/b.wast",
+        );
+        let (a, b) = (repo("a"), repo("b"));
+        let integration = integration::build_integration("integration", &[&a, &b]).unwrap();
+        assert_eq!(
+            integration.conflicts,
+            [(
+                "a".to_owned(),
+                "b".to_owned(),
+                vec!["test/core/a.wast".to_owned(), "test/core/b.wast".to_owned()]
+            )]
+        );
+        assert!(mock
+            .calls()
+            .contains(&"git merge-tree --write-tree --name-only --no-messages a b".to_owned()));
+
+        // Git exits with 128 on errors, rather than the 1 of conflicts
+        mock.exit("git merge-tree", 128, "");
+        assert!(integration::build_integration("integration", &[&a, &b]).is_err());
+    }
+
+    #[test]
+    fn runner_used_by_spawned_threads() {
+        let mock = mock();
+        let names: Vec<String> = (0..4).map(|x| x.to_string()).collect();
+        parallel_map(4, &names, |chunk: &[String]| {
+            for name in chunk {
+                run("git", &["show", name])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let mut calls = mock.calls();
+        calls.sort();
+        assert_eq!(
+            calls,
+            ["git show 0", "git show 1", "git show 2", "git show 3"]
+        );
+    }
+
+    #[test]
+    fn streamed_output_is_captured() {
+        let runner = ProcessRunner;
+        let script = "echo a; echo b >&2; echo c";
+        let output = runner.run_streamed("sh", &["-c", script], &[]).unwrap();
+        assert_eq!(output, "a\nc");
+
+        let err = runner
+            .run_streamed("sh", &["-c", "echo out; echo err >&2; exit 1"], &[])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "sh: out\nerr");
+    }
+
+    #[test]
+    fn invalid_utf8_output_is_replaced() {
+        let output = ProcessRunner
+            .run("sh", &["-c", "printf 'a\\377b'; printf '\\377' >&2"], &[])
+            .unwrap();
+        assert_eq!(output, "a\u{fffd}b");
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use regex::{Regex, RegexSetBuilder};
+
+use crate::notify::json_string;
+use crate::{
+    change_dir, fetch_repos, in_excluded_dir, layout, run, sort_repos, Config, Lock, Repo,
+};
+
+/// The tests that would be generated for a repo
+pub struct Listing {
+    pub name: String,
+    pub commit: String,
+    /// The paths of the tests in the output directory, by kind
+    pub tests: BTreeMap<&'static str, Vec<String>>,
+}
+
+/// List the tests that would be generated for every repo, from its locked
+/// commit or upstream head, without checking anything out or building. Merges,
+/// patches and cherry-picks aren't applied, so the tests a repo changed are
+/// those it changed since it forked from its parents.
+pub fn list_tests(config: &Config, lock: &Lock) -> Result<Vec<Listing>> {
+    let specs_dir = config.specs_dir();
+    if !Path::new(specs_dir).join(".git").exists() {
+        fs::create_dir_all(specs_dir)?;
+        run("git", &["-C", specs_dir, "init"])?;
+    }
+    let _cd = change_dir(specs_dir);
+
+    let repos = sort_repos(config)?;
+    let fetched = fetch_repos(&repos, lock, config.net_jobs())?;
+    let mut commits = HashMap::new();
+    for repo in &repos {
+        let commit = match lock.find_commit(&repo.name) {
+            Some(commit) => commit.to_owned(),
+            None => match &fetched[&repo.name] {
+                Ok(branch_upstream) => run("git", &["rev-parse", "--short", branch_upstream])?,
+                Err(err) => bail!("{}: {:?}", repo.name, err),
+            },
+        };
+        commits.insert(repo.name.as_str(), commit);
+    }
+
+    let mut listings = Vec::new();
+    for repo in repos {
+        let commit = &commits[repo.name.as_str()];
+        let parents: Vec<&str> = repo
+            .parent
+            .iter()
+            .map(|x| commits[x.as_str()].as_str())
+            .collect();
+        let tests = list_repo(repo, config, commit, &parents)
+            .with_context(|| format!("failed to list the tests of {}", repo.name))?;
+        listings.push(Listing {
+            name: repo.name.clone(),
+            commit: commit.clone(),
+            tests,
+        });
+    }
+    Ok(listings)
+}
+
+fn list_repo(
+    repo: &Repo,
+    config: &Config,
+    commit: &str,
+    parents: &[&str],
+) -> Result<BTreeMap<&'static str, Vec<String>>> {
+    let mut extensions: Vec<&str> = config
+        .test_extensions
+        .iter()
+        .chain(&repo.test_extensions)
+        .map(|x| x.as_str())
+        .collect();
+    if extensions.is_empty() {
+        extensions.push("wast");
+    }
+    let input = Regex::new(r#"\(input\s+(?:\$\S+\s+)?"([^"]+)"\s*\)"#).unwrap();
+
+    // Select the tests the same way as when building
+    let mut included_files = Vec::new();
+    for path in files_changed(commit, parents, "test/core")? {
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+        if !extensions.contains(&extension) {
+            continue;
+        }
+        included_files.push(path.file_name().unwrap().to_str().unwrap().to_owned());
+        if extension == "wast" {
+            let text = show(commit, &Path::new("test/core").join(&path))?;
+            for captures in input.captures_iter(&text) {
+                included_files.push(captures[1].to_owned());
+            }
+        }
+    }
+    included_files.extend_from_slice(&config.included_tests);
+    included_files.extend_from_slice(&repo.included_tests);
+    included_files.push("harness/".to_owned());
+    let include = RegexSetBuilder::new(&included_files)
+        .build()
+        .context("invalid included_tests pattern")?;
+
+    let mut excluded_files = Vec::new();
+    excluded_files.extend_from_slice(&config.excluded_tests);
+    excluded_files.extend_from_slice(&repo.excluded_tests);
+    let exclude = RegexSetBuilder::new(&excluded_files)
+        .build()
+        .context("invalid excluded_tests pattern")?;
+    let content =
+        RegexSetBuilder::new(config.excluded_content.iter().chain(&repo.excluded_content))
+            .build()
+            .context("invalid excluded_content pattern")?;
+
+    let mut tests: BTreeMap<&'static str, Vec<String>> = BTreeMap::new();
+    let mut add = |kind: &'static str, path: &Path| {
+        let test = layout::arranged(
+            config.layout(),
+            kind,
+            repo.output_name(),
+            path.to_str().unwrap(),
+        );
+        tests.entry(kind).or_default().push(test);
+    };
+    let mut has_js = false;
+    for path in ls_tree(commit, "test/core")? {
+        let relative = path.to_str().unwrap();
+        if in_excluded_dir(repo, config, relative)
+            || !include.is_match(relative)
+            || exclude.is_match(relative)
+        {
+            continue;
+        }
+        let is_script = path.extension().and_then(|x| x.to_str()) == Some("wast");
+        if is_script
+            && content.len() != 0
+            && content.is_match(&show(commit, &Path::new("test/core").join(&path))?)
+        {
+            continue;
+        }
+
+        if !repo.skip_wast {
+            add("wast", &path);
+        }
+        if is_script && !repo.skip_js {
+            has_js = true;
+            // Tests are assumed to convert to `.any.js`, as the generated
+            // ones do
+            let extension = if config.any_js { "any.js" } else { "js" };
+            add(
+                "js",
+                Path::new(&format!(
+                    "{}.{}",
+                    path.file_name().unwrap().to_str().unwrap(),
+                    extension
+                )),
+            );
+        }
+        if is_script && !repo.skip_json && config.wast2json.is_some() {
+            add("json", &path.with_extension("json"));
+        }
+    }
+    if has_js {
+        add("js", Path::new("harness/harness.js"));
+    }
+
+    // JS API tests are selected by their path, with the helper scripts
+    if !repo.skip_js_api {
+        let changed = files_changed(commit, parents, "test/js-api")?;
+        for path in ls_tree(commit, "test/js-api")? {
+            let relative = path.to_str().unwrap();
+            if (changed.contains(&path) || !relative.ends_with(".any.js"))
+                && !in_excluded_dir(repo, config, relative)
+                && !exclude.is_match(relative)
+            {
+                add("js-api", &path);
+            }
+        }
+    }
+
+    for dir in &repo.extra_dirs {
+        let dir = dir.trim_end_matches('/');
+        for path in ls_tree(commit, dir)? {
+            add("extra", &Path::new(dir).join(path));
+        }
+    }
+    Ok(tests)
+}
+
+// The files below a directory at a commit, relative to the directory
+fn ls_tree(commit: &str, dir: &str) -> Result<Vec<PathBuf>> {
+    let prefix = format!("{}/", dir);
+    Ok(run(
+        "git",
+        &["ls-tree", "-r", "--name-only", commit, "--", &prefix],
+    )?
+    .lines()
+    .filter_map(|x| x.strip_prefix(&prefix))
+    .map(PathBuf::from)
+    .collect())
+}
+
+// The files below a directory that a commit changed since it forked from every
+// parent, or all of them if there are no parents
+fn files_changed(commit: &str, parents: &[&str], dir: &str) -> Result<Vec<PathBuf>> {
+    if parents.is_empty() {
+        return ls_tree(commit, dir);
+    }
+
+    let prefix = format!("{}/", dir);
+    let mut files_changed: Option<Vec<PathBuf>> = None;
+    for parent in parents {
+        let range = format!("{}...{}", parent, commit);
+        let diff: Vec<PathBuf> = run("git", &["diff", "--name-only", &range, "--", &prefix])?
+            .lines()
+            .filter_map(|x| x.strip_prefix(&prefix))
+            .map(PathBuf::from)
+            .collect();
+        files_changed = Some(match files_changed {
+            Some(files) => files.into_iter().filter(|x| diff.contains(x)).collect(),
+            None => diff,
+        });
+    }
+    Ok(files_changed.unwrap())
+}
+
+// The contents of a file at a commit
+fn show(commit: &str, path: &Path) -> Result<String> {
+    run("git", &["show", &format!("{}:{}", commit, path.display())])
+}
+
+/// The listings as a JSON object, keyed by repo
+pub fn to_json(listings: &[Listing]) -> String {
+    let repos: Vec<String> = listings
+        .iter()
+        .map(|listing| {
+            let mut fields = vec![format!("\"commit\":{}", json_string(&listing.commit))];
+            for (kind, tests) in &listing.tests {
+                let tests: Vec<String> = tests.iter().map(|x| json_string(x)).collect();
+                fields.push(format!("{}:[{}]", json_string(kind), tests.join(",")));
+            }
+            format!("{}:{{{}}}", json_string(&listing.name), fields.join(","))
+        })
+        .collect();
+    format!("{{{}}}", repos.join(","))
+}
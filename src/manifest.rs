@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::duplicates::Duplicate;
+use crate::{find, run, Repo, KINDS};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    #[serde(default)]
+    file: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ManifestFile {
+    /// The output file, relative to the output directory
+    pub path: String,
+    pub repo: String,
+    /// The commit the tests were built from, after merging with any parents
+    pub commit: String,
+    /// The file in the repo that was copied or converted, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Whether the repo's build script failed, and this was converted by the
+    /// fallback generator instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+}
+
+impl Manifest {
+    /// Load the `MANIFEST.toml` of an output directory.
+    pub fn load(output_dir: &Path) -> Result<Manifest> {
+        let path = output_dir.join("MANIFEST.toml");
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    pub fn files(&self) -> &[ManifestFile] {
+        &self.file
+    }
+
+    /// Add every output file of a repo, or of one of its variants, built from
+    /// `commit`, flagging the `.js` tests if `fallback_js`. Must be run from
+    /// the specs directory.
+    pub fn add_repo(
+        &mut self,
+        output_dir: &Path,
+        variant: Option<&str>,
+        repo: &Repo,
+        commit: &str,
+        fallback_js: bool,
+    ) -> Result<()> {
+        // Generated tests are flattened, so find their sources by file name
+        let mut wast_sources = HashMap::new();
+        let paths = run(
+            "git",
+            &["ls-tree", "-r", "--name-only", commit, "test/core"],
+        )?;
+        for path in paths.lines() {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            wast_sources.insert(name.to_owned(), path.to_owned());
+        }
+
+        let prefix = match variant {
+            Some(variant) => format!("{}/", variant),
+            None => String::new(),
+        };
+        for kind in KINDS {
+            let dir = output_dir.join(&prefix).join(kind).join(repo.output_name());
+            if !dir.exists() {
+                continue;
+            }
+
+            for path in find(dir.to_str().unwrap()) {
+                let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+                let source = match *kind {
+                    "wast" => Some(format!("test/core/{}", relative)),
+                    "js-api" => Some(format!("test/js-api/{}", relative)),
+                    "json" => json_source(relative),
+                    _ => relative
+                        .strip_suffix(".any.js")
+                        .or_else(|| relative.strip_suffix(".js"))
+                        .and_then(|x| wast_sources.get(x))
+                        .cloned(),
+                };
+                self.file.push(ManifestFile {
+                    path: format!("{}{}/{}/{}", prefix, kind, repo.output_name(), relative),
+                    repo: repo.name().to_owned(),
+                    commit: commit.to_owned(),
+                    source,
+                    fallback: fallback_js && *kind == "js",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the files that are no longer in the output directory, such as
+    /// omitted tests.
+    pub fn retain_existing(&mut self, output_dir: &Path) {
+        self.file.retain(|x| output_dir.join(&x.path).exists());
+    }
+
+    /// Point the files moved into another layout at their new path.
+    pub fn relocate(&mut self, moved: &BTreeMap<String, String>) {
+        for file in &mut self.file {
+            if let Some(path) = moved.get(&file.path) {
+                file.path = path.clone();
+            }
+        }
+    }
+
+    /// Point the files moved into the shared directory at their new path.
+    pub fn deduplicate(&mut self, duplicates: &[&Duplicate], shared: &str) {
+        for duplicate in duplicates {
+            for file in &mut self.file {
+                let is_copy = duplicate.repos.iter().any(|repo| {
+                    file.path == format!("{}/{}/{}", duplicate.kind, repo, duplicate.path)
+                });
+                if is_copy {
+                    file.path = format!("{}/{}/{}", duplicate.kind, shared, duplicate.path);
+                }
+            }
+        }
+    }
+}
+
+// The script a file converted to JSON is from. The modules of `${name}.json`
+// are named `${name}.${index}.wasm`, or `.wat` for text modules.
+fn json_source(relative: &str) -> Option<String> {
+    let name = match relative.strip_suffix(".json") {
+        Some(name) => name,
+        None => {
+            let module = relative
+                .strip_suffix(".wasm")
+                .or_else(|| relative.strip_suffix(".wat"))?;
+            let (name, index) = module.rsplit_once('.')?;
+            if index.is_empty() || !index.bytes().all(|x| x.is_ascii_digit()) {
+                return None;
+            }
+            name
+        }
+    };
+    Some(format!("test/core/{}.wast", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_sources() {
+        let source = json_source;
+        assert_eq!(source("foo.json").as_deref(), Some("test/core/foo.wast"));
+        assert_eq!(source("foo.0.wasm").as_deref(), Some("test/core/foo.wast"));
+        assert_eq!(
+            source("foo.bar.12.wat").as_deref(),
+            Some("test/core/foo.bar.wast")
+        );
+        assert_eq!(
+            source("sub.dir/foo.json").as_deref(),
+            Some("test/core/sub.dir/foo.wast")
+        );
+        assert_eq!(
+            source("sub.dir/foo.1.wasm").as_deref(),
+            Some("test/core/sub.dir/foo.wast")
+        );
+        assert_eq!(source("sub.dir/notes.txt"), None);
+    }
+}
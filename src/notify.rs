@@ -0,0 +1,218 @@
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{runner, Merge, Status, VariantStatus, Version};
+
+// The number of lines of a failed build's output to include
+const LOG_TAIL_LINES: usize = 40;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    url: String,
+    #[serde(default)]
+    format: WebhookFormat,
+    /// The environment variable holding a bearer token to send, if any
+    #[serde(default)]
+    token_env: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WebhookFormat {
+    #[default]
+    Generic,
+    Slack,
+    Matrix,
+}
+
+/// A repo, or a variant of one, that newly failed to merge or build
+pub struct Event {
+    name: String,
+    commit: String,
+    /// The parent that failed to merge, and the conflicted files
+    conflict: Option<(String, Vec<String>)>,
+    /// The tail of the build output, if it failed to build
+    log: Option<String>,
+}
+
+impl Event {
+    fn status(&self) -> &'static str {
+        if self.conflict.is_some() {
+            "conflicted"
+        } else {
+            "broken"
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!("{} is now {} at {}", self.name, self.status(), self.commit);
+        if let Some((parent, files)) = &self.conflict {
+            text.push_str(&format!(" merging with {}\n\nConflicted files:\n", parent));
+            for file in files {
+                text.push_str(&format!("- {}\n", file));
+            }
+        }
+        if let Some(log) = &self.log {
+            text.push_str(&format!("\n\nBuild output:\n```\n{}\n```\n", log));
+        }
+        text
+    }
+}
+
+/// Find the repos and variants that are conflicted or broken, but weren't in
+/// the previous tests. Repos that weren't in the previous tests count as having
+/// merged and built.
+pub fn events(previous: Option<&Version>, successes: &[(String, Status)]) -> Vec<Event> {
+    let was_ok = |name: &str, status: &str| match previous
+        .and_then(|x| x.repos.iter().find(|x| x.name == name))
+    {
+        Some(repo) if status == "conflicted" => repo.merged != "conflicted",
+        Some(repo) => repo.built,
+        None => true,
+    };
+
+    let mut events = Vec::new();
+    for (name, status) in successes {
+        let mut outputs = vec![(
+            name.clone(),
+            &status.merged,
+            status.built,
+            &status.build_log,
+        )];
+        for VariantStatus {
+            name: variant,
+            merged,
+            built,
+            build_log,
+            ..
+        } in &status.variants
+        {
+            outputs.push((format!("{}/{}", name, variant), merged, *built, build_log));
+        }
+
+        for (name, merged, built, build_log) in outputs {
+            let conflict = match merged {
+                Merge::Conflicted(conflict) => {
+                    Some((conflict.parent.clone(), conflict.files.clone()))
+                }
+                _ => None,
+            };
+            let event = Event {
+                name,
+                commit: status.commit_base_hash.clone(),
+                log: if built {
+                    None
+                } else {
+                    build_log.as_deref().map(tail)
+                },
+                conflict,
+            };
+            if (event.conflict.is_some() || !built) && was_ok(&event.name, event.status()) {
+                events.push(event);
+            }
+        }
+    }
+    events
+}
+
+// The last lines of a build's output
+fn tail(log: &str) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n")
+}
+
+/// Send an event to a webhook
+pub fn send(webhook: &Webhook, event: &Event) -> Result<()> {
+    let text = event.text();
+    let (method, url, payload) = match webhook.format {
+        WebhookFormat::Generic => {
+            let files = match &event.conflict {
+                Some((_, files)) => files.iter().map(|x| json_string(x)).collect(),
+                None => Vec::new(),
+            };
+            let payload = format!(
+                "{{\"repo\":{},\"commit\":{},\"status\":{},\"parent\":{},\"files\":[{}],\"log\":{},\"text\":{}}}",
+                json_string(&event.name),
+                json_string(&event.commit),
+                json_string(event.status()),
+                event
+                    .conflict
+                    .as_ref()
+                    .map(|(x, _)| json_string(x))
+                    .unwrap_or_else(|| "null".to_owned()),
+                files.join(","),
+                event
+                    .log
+                    .as_deref()
+                    .map(json_string)
+                    .unwrap_or_else(|| "null".to_owned()),
+                json_string(&text),
+            );
+            ("POST", webhook.url.clone(), payload)
+        }
+        WebhookFormat::Slack => (
+            "POST",
+            webhook.url.clone(),
+            format!("{{\"text\":{}}}", json_string(&text)),
+        ),
+        // Matrix messages are sent to a room's `send/m.room.message` endpoint,
+        // with a unique transaction id
+        WebhookFormat::Matrix => {
+            let txn = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+            (
+                "PUT",
+                format!("{}/wgt-{}", webhook.url.trim_end_matches('/'), txn),
+                format!("{{\"msgtype\":\"m.text\",\"body\":{}}}", json_string(&text)),
+            )
+        }
+    };
+
+    // Pass the token on stdin, so that it isn't visible in the arguments
+    let headers = match &webhook.token_env {
+        Some(name) => {
+            let token = env::var(name).with_context(|| format!("{} is not set", name))?;
+            format!("Authorization: Bearer {}\n", token)
+        }
+        None => String::new(),
+    };
+    runner::current().run_input(
+        "curl",
+        &[
+            "-sS",
+            "-f",
+            "-X",
+            method,
+            "-H",
+            "Content-Type: application/json",
+            "-H",
+            "@-",
+            "--data-binary",
+            &payload,
+            &url,
+        ],
+        &[],
+        &headers,
+    )?;
+    Ok(())
+}
+
+/// Quote a string as a JSON string
+pub fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
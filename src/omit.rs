@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{find, Config, DIRECTIVES_FILES, KINDS};
+
+pub struct Omission {
+    /// The omitted file, relative to the output directory of the repo
+    pub path: String,
+    /// The ancestor repo providing an identical file
+    pub ancestor: String,
+}
+
+/// Remove the copied tests of each repo that are byte-identical to the copied
+/// tests of one of its ancestors, returning the omitted files of each repo.
+/// Harness files and directives are always kept, as the remaining tests need
+/// them.
+pub fn omit_identical(config: &Config, output_dir: &Path) -> Result<Vec<(String, Vec<Omission>)>> {
+    let mut omitted = Vec::new();
+    for repo in &config.repos {
+        let ancestors = config.ancestors(repo);
+        if ancestors.is_empty() {
+            continue;
+        }
+
+        let mut repo_omitted = Vec::new();
+        for kind in KINDS {
+            let dir = output_dir.join(kind).join(repo.output_name());
+            if !dir.exists() {
+                continue;
+            }
+
+            for path in find(dir.to_str().unwrap()) {
+                let relative = path.strip_prefix(&dir).unwrap();
+                if relative.starts_with("harness")
+                    || DIRECTIVES_FILES.iter().any(|x| relative == Path::new(x))
+                {
+                    continue;
+                }
+
+                let contents = fs::read(&path)?;
+                let identical = ancestors.iter().find(|ancestor| {
+                    let ancestor = config.find_repo(ancestor).unwrap().output_name();
+                    let ancestor_path = output_dir.join(kind).join(ancestor).join(relative);
+                    fs::read(ancestor_path).is_ok_and(|x| x == contents)
+                });
+                if let Some(ancestor) = identical {
+                    fs::remove_file(&path)?;
+                    repo_omitted.push(Omission {
+                        path: format!("{}/{}", kind, relative.display()),
+                        ancestor: ancestor.to_string(),
+                    });
+                }
+            }
+        }
+        if !repo_omitted.is_empty() {
+            omitted.push((repo.name.clone(), repo_omitted));
+        }
+    }
+    Ok(omitted)
+}
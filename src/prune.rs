@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{change_dir, generated_cache_dir, recover_specs, run, Config};
+
+/// Remove the remotes, branches, cherry-pick and locked refs, and cached tests
+/// of repos that are no longer in the config, returning what was removed.
+pub fn prune(config: &Config) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    if !Path::new(config.specs_dir()).exists() {
+        return Ok(removed);
+    }
+    let _cd = change_dir(config.specs_dir());
+    recover_specs()?;
+    let is_stale = |name: &str| config.find_repo(name).is_none();
+
+    // A branch can't be deleted while it's checked out. This fails if nothing
+    // has been checked out yet, in which case there are no branches.
+    let _ = run("git", &["checkout", "--detach"]);
+
+    for remote in run("git", &["remote"])?.lines().filter(|x| is_stale(x)) {
+        run("git", &["remote", "remove", remote])?;
+        removed.push(format!("remote {}", remote));
+    }
+
+    let branches = run(
+        "git",
+        &["for-each-ref", "--format=%(refname:short)", "refs/heads"],
+    )?;
+    for branch in branches.lines() {
+        // The integration branch isn't named after a repo
+        if !is_stale(branch) || config.integration_branch.as_deref() == Some(branch) {
+            continue;
+        }
+        run("git", &["branch", "-D", branch])?;
+        removed.push(format!("branch {}", branch));
+    }
+
+    let refs = run(
+        "git",
+        &[
+            "for-each-ref",
+            "--format=%(refname)",
+            "refs/cherry-picks",
+            "refs/locked",
+        ],
+    )?;
+    for reference in refs.lines() {
+        let name = reference.split('/').nth(2).unwrap_or_default();
+        if is_stale(name) {
+            run("git", &["update-ref", "-d", reference])?;
+            removed.push(format!("ref {}", reference));
+        }
+    }
+
+    let cache_dir = generated_cache_dir("");
+    if cache_dir.exists() {
+        for entry in fs::read_dir(&cache_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_str().unwrap().to_owned();
+            if is_stale(&name) {
+                fs::remove_dir_all(entry.path())?;
+                removed.push(format!("cached tests {}", name));
+            }
+        }
+    }
+    Ok(removed)
+}
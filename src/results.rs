@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{change_dir, find, runner, Config};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Timeout,
+}
+
+/// The outcome of every generated test, by repo and then by test path
+pub type Results = BTreeMap<String, BTreeMap<String, Outcome>>;
+
+/// Run every generated `.js` test under the configured shell, from the
+/// directory of its repo so the harness can be loaded relative to it.
+pub fn run_tests(config: &Config, shell: &str, output_dir: &Path) -> Result<Results> {
+    let mut results = Results::new();
+    for repo in &config.repos {
+        let dir = output_dir.join("js").join(repo.output_name());
+        if !dir.exists() {
+            continue;
+        }
+
+        // Pass along the shell flags from the directives
+        let directives = config.directives(repo);
+        let flags: Vec<&str> = directives
+            .split(|x: char| x == ';' || x.is_whitespace())
+            .filter(|x| x.starts_with("--"))
+            .collect();
+
+        let paths = find(dir.to_str().unwrap());
+        let env = [("WGT_FLAGS".to_owned(), flags.join(" "))];
+        let timeout = config.shell_timeout().to_string();
+        let _cd = change_dir(dir.to_str().unwrap());
+        let mut repo_results = BTreeMap::new();
+        for path in paths {
+            let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+            if relative.starts_with("harness/") || !relative.ends_with(".js") {
+                continue;
+            }
+
+            let output = runner::current().run_status(
+                "timeout",
+                &[&timeout, "sh", "-c", shell, "sh", relative],
+                &env,
+            )?;
+            let outcome = match output.code {
+                Some(0) => Outcome::Pass,
+                Some(124) => Outcome::Timeout,
+                _ => Outcome::Fail,
+            };
+            debug!("{}/{}: {:?}", repo.name, relative, outcome);
+            repo_results.insert(relative.to_owned(), outcome);
+        }
+        results.insert(repo.name.clone(), repo_results);
+    }
+    Ok(results)
+}
+
+/// The differences between the expected outcome of each test and a run
+#[derive(Debug, Default)]
+pub struct Comparison {
+    /// Tests that didn't pass, and weren't expected to fail that way
+    pub regressed: Vec<(String, String, Outcome)>,
+    /// Tests expected to fail that now pass, or fail differently
+    pub changed: Vec<(String, String, Outcome)>,
+}
+
+/// Load the expected outcome of the tests that don't pass, defaulting to
+/// every test passing if there are no expectations yet.
+pub fn load_expectations(path: &Path) -> Result<Results> {
+    if !path.exists() {
+        return Ok(Results::new());
+    }
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// The expectations for a run, which are the tests that didn't pass.
+pub fn expectations(results: &Results) -> Results {
+    let mut expectations = Results::new();
+    for (name, repo_results) in results {
+        let failing: BTreeMap<String, Outcome> = repo_results
+            .iter()
+            .filter(|(_, x)| **x != Outcome::Pass)
+            .map(|(test, x)| (test.clone(), *x))
+            .collect();
+        if !failing.is_empty() {
+            expectations.insert(name.clone(), failing);
+        }
+    }
+    expectations
+}
+
+pub fn compare(expected: &Results, results: &Results) -> Comparison {
+    let mut comparison = Comparison::default();
+    for (name, repo_results) in results {
+        for (test, outcome) in repo_results {
+            let expected = expected
+                .get(name)
+                .and_then(|x| x.get(test))
+                .copied()
+                .unwrap_or(Outcome::Pass);
+            if expected == *outcome {
+                continue;
+            }
+            let entry = (name.clone(), test.clone(), *outcome);
+            if *outcome == Outcome::Pass || expected != Outcome::Pass {
+                comparison.changed.push(entry);
+            } else {
+                comparison.regressed.push(entry);
+            }
+        }
+    }
+    comparison
+}
+
+/// Format the expected failures with a line template, where `{repo}`,
+/// `{test}` and `{outcome}` are replaced for each test.
+pub fn skip_list(template: &str, expectations: &Results) -> String {
+    let mut list = String::new();
+    for (name, repo_results) in expectations {
+        for (test, outcome) in repo_results {
+            let outcome = match outcome {
+                Outcome::Pass => "pass",
+                Outcome::Fail => "fail",
+                Outcome::Timeout => "timeout",
+            };
+            list.push_str(
+                &template
+                    .replace("{repo}", name)
+                    .replace("{test}", test)
+                    .replace("{outcome}", outcome),
+            );
+            list.push('\n');
+        }
+    }
+    list
+}
@@ -0,0 +1,254 @@
+use std::cell::RefCell;
+use std::io::{BufRead as _, BufReader, Read, Write as _};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use log::{debug, log, Level};
+
+/// The exit code and trimmed output of a command that ran
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// `None` if the command was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs an external command, returning its trimmed stdout if it succeeds. Every
+/// command, such as git, is run through one, so that the logic around them can
+/// be tested without real repos or a network.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<String>;
+
+    /// Like `run`, but shows the output as it's written, for long-running
+    /// commands such as build hooks
+    fn run_streamed(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<String> {
+        self.run(name, args, env)
+    }
+
+    /// Like `run`, but writes `input` to the command's stdin, for secrets that
+    /// mustn't be visible in the arguments
+    fn run_input(
+        &self,
+        name: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        _input: &str,
+    ) -> Result<String> {
+        self.run(name, args, env)
+    }
+
+    /// Like `run`, but returns the exit code and output rather than failing if
+    /// the command doesn't succeed, for commands whose exit code has a meaning
+    fn run_status(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<Output>;
+}
+
+/// Runs commands as processes. Their output is logged line by line as it's
+/// written, prefixed with the label of the thread if any, and invalid UTF-8 in
+/// it is replaced.
+pub struct ProcessRunner;
+
+impl ProcessRunner {
+    // Run a command, logging its output at `level` while capturing it
+    fn run_logged(
+        &self,
+        name: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        input: Option<&str>,
+        level: Level,
+    ) -> Result<Output> {
+        debug!("{} {:?}", name, args);
+        let mut child = Command::new(name)
+            .args(args)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(input) = input {
+            child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        }
+        let child_stdout = child.stdout.take().unwrap();
+        let child_stderr = child.stderr.take().unwrap();
+        let prefix = label().map(|x| format!("[{}] ", x)).unwrap_or_default();
+        let (stdout, stderr) = std::thread::scope(|scope| {
+            let stderr = scope.spawn(|| read_logged(child_stderr, level, &prefix));
+            let stdout = read_logged(child_stdout, level, &prefix);
+            (stdout, stderr.join().unwrap())
+        });
+        let status = child.wait()?;
+        // Output that isn't UTF-8 is kept as well as possible, rather than
+        // failing a command that otherwise worked
+        Ok(Output {
+            code: status.code(),
+            stdout: String::from_utf8_lossy(&stdout?).trim().to_owned(),
+            stderr: String::from_utf8_lossy(&stderr?).trim().to_owned(),
+        })
+    }
+}
+
+// The stdout of a command if it succeeded
+fn succeeded(name: &str, output: Output) -> Result<String> {
+    if output.code == Some(0) {
+        Ok(output.stdout)
+    } else {
+        bail!("{}: {}\n{}", name, output.stdout, output.stderr)
+    }
+}
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<String> {
+        succeeded(name, self.run_logged(name, args, env, None, Level::Debug)?)
+    }
+
+    fn run_streamed(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<String> {
+        succeeded(name, self.run_logged(name, args, env, None, Level::Info)?)
+    }
+
+    fn run_input(
+        &self,
+        name: &str,
+        args: &[&str],
+        env: &[(String, String)],
+        input: &str,
+    ) -> Result<String> {
+        succeeded(
+            name,
+            self.run_logged(name, args, env, Some(input), Level::Debug)?,
+        )
+    }
+
+    fn run_status(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<Output> {
+        self.run_logged(name, args, env, None, Level::Debug)
+    }
+}
+
+// Read the output of a process to the end, logging each line as it's read
+fn read_logged(output: impl Read, level: Level, prefix: &str) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(output);
+    let mut captured = Vec::new();
+    loop {
+        let start = captured.len();
+        if reader.read_until(b'\n', &mut captured)? == 0 {
+            return Ok(captured);
+        }
+        let line = String::from_utf8_lossy(&captured[start..]);
+        log!(level, "{}{}", prefix, line.trim_end());
+    }
+}
+
+/// Records the commands run, and answers them from canned responses instead of
+/// running anything. Commands without a response succeed with no output, and
+/// failing responses exit with 1.
+#[derive(Default)]
+pub struct MockRunner {
+    responses: Mutex<Vec<(String, Output)>>,
+    calls: Mutex<Vec<String>>,
+}
+
+impl MockRunner {
+    pub fn new() -> MockRunner {
+        MockRunner::default()
+    }
+
+    /// Answer the commands starting with `prefix`, e.g. `git ls-remote`, with
+    /// `output`. Later responses take precedence.
+    pub fn respond(&self, prefix: &str, output: &str) {
+        self.exit(prefix, 0, output);
+    }
+
+    /// Fail the commands starting with `prefix` with `error`
+    pub fn fail(&self, prefix: &str, error: &str) {
+        let mut responses = self.responses.lock().unwrap();
+        responses.push((
+            prefix.to_owned(),
+            Output {
+                code: Some(1),
+                stdout: String::new(),
+                stderr: error.to_owned(),
+            },
+        ));
+    }
+
+    /// Answer the commands starting with `prefix` with the exit code `code`
+    /// and `output`, for commands whose exit code has a meaning
+    pub fn exit(&self, prefix: &str, code: i32, output: &str) {
+        let mut responses = self.responses.lock().unwrap();
+        responses.push((
+            prefix.to_owned(),
+            Output {
+                code: Some(code),
+                stdout: output.to_owned(),
+                stderr: String::new(),
+            },
+        ));
+    }
+
+    /// The commands run so far, as the command and its arguments separated by
+    /// spaces
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn run(&self, name: &str, args: &[&str], env: &[(String, String)]) -> Result<String> {
+        let output = self.run_status(name, args, env)?;
+        if output.code != Some(0) {
+            bail!("{}: {}", name, output.stderr);
+        }
+        Ok(output.stdout)
+    }
+
+    fn run_status(&self, name: &str, args: &[&str], _env: &[(String, String)]) -> Result<Output> {
+        let mut command = vec![name];
+        command.extend(args);
+        let command = command.join(" ");
+        self.calls.lock().unwrap().push(command.clone());
+
+        let responses = self.responses.lock().unwrap();
+        Ok(
+            match responses.iter().rev().find(|(x, _)| command.starts_with(x)) {
+                Some((_, output)) => output.clone(),
+                None => Output {
+                    code: Some(0),
+                    stdout: String::new(),
+                    stderr: String::new(),
+                },
+            },
+        )
+    }
+}
+
+thread_local! {
+    static RUNNER: RefCell<Arc<dyn CommandRunner>> = RefCell::new(Arc::new(ProcessRunner));
+    static LABEL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The runner for the current thread
+pub fn current() -> Arc<dyn CommandRunner> {
+    RUNNER.with(|x| x.borrow().clone())
+}
+
+/// Run the commands of the current thread, and the threads it spawns to run
+/// commands, with `runner`. Returns the previous runner.
+pub fn set_runner(runner: Arc<dyn CommandRunner>) -> Arc<dyn CommandRunner> {
+    RUNNER.with(|x| x.replace(runner))
+}
+
+/// The label of the current thread, such as the repo it's working on
+pub fn label() -> Option<String> {
+    LABEL.with(|x| x.borrow().clone())
+}
+
+/// Label the output of the commands run by the current thread, to tell apart
+/// the output of commands running in parallel. Returns the previous label.
+pub fn set_label(label: Option<&str>) -> Option<String> {
+    LABEL.with(|x| x.replace(label.map(|x| x.to_owned())))
+}
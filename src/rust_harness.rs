@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{find, write_string, Config};
+
+const CARGO_TOML: &str = r#"[package]
+name = "wasm-generated-tests"
+version = "0.1.0"
+edition = "2018"
+publish = false
+
+[features]
+wasmtime = ["dep:wasmtime", "dep:wasmtime-wast"]
+
+[dependencies]
+wasmtime = { version = "20", optional = true }
+wasmtime-wast = { version = "20", optional = true }
+"#;
+
+const LIB_HEADER: &str = r#"//! The generated `.wast` tests, with a `#[test]` for each of them.
+//!
+//! Implement `Runner` for a runtime, then generate the tests for it with
+//! `wasm_generated_tests::spec_tests!(MyRunner);` in a test file. A runner for
+//! wasmtime is provided behind the `wasmtime` feature.
+
+/// Runs a single `.wast` script, returning a description of any failure.
+pub trait Runner {
+    fn run(&self, name: &str, wast: &str) -> Result<(), String>;
+}
+
+/// Run a test by name, panicking if it fails.
+pub fn run<R: Runner>(runner: &R, name: &str) {
+    let (_, wast) = TESTS.iter().find(|(x, _)| *x == name).unwrap();
+    if let Err(err) = runner.run(name, wast) {
+        panic!("{}: {}", name, err);
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+pub struct Wasmtime;
+
+#[cfg(feature = "wasmtime")]
+impl Runner for Wasmtime {
+    fn run(&self, name: &str, wast: &str) -> Result<(), String> {
+        let engine = wasmtime::Engine::default();
+        let store = wasmtime::Store::new(&engine, ());
+        let mut context = wasmtime_wast::WastContext::new(store);
+        context
+            .register_spectest(&wasmtime_wast::SpectestConfig {
+                use_shared_memory: true,
+                suppress_prints: true,
+            })
+            .map_err(|x| format!("{:?}", x))?;
+        context
+            .run_buffer(name, wast.as_bytes())
+            .map_err(|x| format!("{:?}", x))
+    }
+}
+"#;
+
+const WASMTIME_TESTS: &str = r#"#[cfg(feature = "wasmtime")]
+wasm_generated_tests::spec_tests!(wasm_generated_tests::Wasmtime);
+"#;
+
+/// Write a Cargo crate into `rust/` of the output directory, with a test for
+/// every copied `.wast` run by a pluggable runner.
+pub fn write_crate(config: &Config, output_dir: &Path) -> Result<()> {
+    let mut tests = String::from("pub const TESTS: &[(&str, &str)] = &[\n");
+    let mut modules = String::new();
+    let mut module_names = HashSet::new();
+    // Tests moved into the shared directory are included with the repos
+    let names = config
+        .repos
+        .iter()
+        .map(|x| x.output_name())
+        .chain(config.dedup_dir.as_deref());
+    for repo in names {
+        let dir = output_dir.join("wast").join(repo);
+        if !dir.exists() {
+            continue;
+        }
+
+        let paths = find(dir.to_str().unwrap());
+        let mut functions = String::new();
+        let mut function_names = HashSet::new();
+        for path in paths {
+            let relative = path.strip_prefix(&dir).unwrap().to_str().unwrap();
+            if !relative.ends_with(".wast") {
+                continue;
+            }
+
+            let name = format!("{}/{}", repo, relative);
+            writeln!(
+                tests,
+                "    ({:?}, include_str!(\"../../wast/{}\")),",
+                name, name
+            )?;
+            let function = unique(
+                identifier(relative.trim_end_matches(".wast")),
+                &mut function_names,
+            );
+            writeln!(
+                functions,
+                "            #[test]\n            fn {}_wast() {{\n                $crate::run(&$runner, {:?});\n            }}",
+                function, name
+            )?;
+        }
+        writeln!(
+            modules,
+            "        #[allow(non_snake_case, unused_imports)]\n        mod {} {{\n            use super::*;\n{}        }}",
+            unique(identifier(repo), &mut module_names),
+            functions
+        )?;
+    }
+    tests.push_str("];\n");
+
+    let macro_rules = format!(
+        "/// Generate a `#[test]` for every test, run with `$runner`.\n#[macro_export]\nmacro_rules! spec_tests {{\n    ($runner:expr) => {{\n{}    }};\n}}\n",
+        modules
+    );
+
+    let crate_dir = output_dir.join("rust");
+    write_string(crate_dir.join("Cargo.toml"), CARGO_TOML)?;
+    write_string(
+        crate_dir.join("src/lib.rs"),
+        &format!("{}\n{}\n{}", LIB_HEADER, tests, macro_rules),
+    )?;
+    write_string(crate_dir.join("tests/wasmtime.rs"), WASMTIME_TESTS)?;
+    Ok(())
+}
+
+// A Rust identifier for a test or repo name, which may be the same for
+// different names
+fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+        .collect();
+    if identifier.starts_with(|x: char| x.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+// An identifier that isn't in `used` yet, numbering it if needed
+fn unique(identifier: String, used: &mut HashSet<String>) -> String {
+    let mut unique = identifier.clone();
+    let mut number = 1;
+    while !used.insert(unique.clone()) {
+        number += 1;
+        unique = format!("{}_{}", identifier, number);
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn colliding_identifiers() {
+        let output_dir = env::temp_dir().join(format!("wgt-rust-harness-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        for path in &["a-b.wast", "a_b.wast", "simd/x.wast", "simd_x.wast"] {
+            write_string(output_dir.join("wast/a-b").join(path), "(module)\n").unwrap();
+        }
+        write_string(output_dir.join("wast/a_b/c.wast"), "(module)\n").unwrap();
+        let config: Config = toml::from_str(
+            "[[repos]]\nname = \"a-b\"\nurl = \"\"\n\n[[repos]]\nname = \"a_b\"\nurl = \"\"\n",
+        )
+        .unwrap();
+
+        write_crate(&config, &output_dir).unwrap();
+        let lib = fs::read_to_string(output_dir.join("rust/src/lib.rs")).unwrap();
+        fs::remove_dir_all(&output_dir).unwrap();
+        for function in &["a_b_wast", "a_b_2_wast", "simd_x_wast", "simd_x_2_wast"] {
+            assert_eq!(
+                lib.matches(&format!("fn {}()", function)).count(),
+                1,
+                "{}",
+                lib
+            );
+        }
+        assert!(lib.contains("mod a_b {"));
+        assert!(lib.contains("mod a_b_2 {"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+use crate::{change_dir, fetch_repos, run, Config, Lock, Repo};
+
+/// The upstream changes of a repo that aren't in the lock yet
+pub struct Pending {
+    /// The locked commit, if any
+    pub pinned: Option<String>,
+    pub upstream: String,
+    /// The number of upstream commits after the locked commit
+    pub commits: usize,
+    /// The files changed in `test/` by those commits
+    pub tests_changed: Vec<String>,
+}
+
+/// Fetch every repo and compare its upstream branch against the locked commit,
+/// without checking anything out or building. Fails if the specs directory
+/// can't be set up, and otherwise returns the result of each repo.
+pub fn pending(config: &Config, lock: &Lock) -> Result<Vec<(String, Result<Pending>)>> {
+    let specs_dir = config.specs_dir();
+    if !Path::new(specs_dir).join(".git").exists() {
+        fs::create_dir_all(specs_dir).with_context(|| format!("failed to create {}", specs_dir))?;
+        run("git", &["-C", specs_dir, "init"])
+            .with_context(|| format!("failed to initialize {}", specs_dir))?;
+    }
+    let _cd = change_dir(specs_dir);
+
+    let repos: Vec<&Repo> = config.repos.iter().collect();
+    let mut fetched = fetch_repos(&repos, lock, config.net_jobs())?;
+    Ok(repos
+        .iter()
+        .map(|repo| {
+            let pending = fetched
+                .remove(&repo.name)
+                .unwrap()
+                .and_then(|x| pending_repo(repo, &x, lock));
+            (repo.name.clone(), pending)
+        })
+        .collect())
+}
+
+fn pending_repo(repo: &Repo, branch_upstream: &str, lock: &Lock) -> Result<Pending> {
+    let upstream = run("git", &["rev-parse", "--short", branch_upstream])?;
+    let pinned = match lock.find_commit(&repo.name) {
+        Some(pinned) => pinned.to_owned(),
+        None => {
+            return Ok(Pending {
+                pinned: None,
+                upstream,
+                commits: 0,
+                tests_changed: Vec::new(),
+            })
+        }
+    };
+
+    let range = format!("{}..{}", pinned, branch_upstream);
+    let commits = run("git", &["rev-list", "--count", &range])?.parse()?;
+    let files: BTreeSet<String> = run(
+        "git",
+        &["log", "--format=", "--name-only", &range, "--", "test/"],
+    )?
+    .lines()
+    .filter(|x| !x.is_empty())
+    .map(|x| x.to_owned())
+    .collect();
+
+    Ok(Pending {
+        pinned: Some(pinned),
+        upstream,
+        commits,
+        tests_changed: files.into_iter().collect(),
+    })
+}
@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::manifest::{Manifest, ManifestFile};
+use crate::{change_dir, run, Config};
+
+/// Describe where every output file matching `test` came from: the repo and
+/// commit it was built from, its source, and whether the source differs from
+/// the parent repos. `test` may be a path in the output directory or in a
+/// repo, or the end of one such as a file name. Returns `None` if nothing
+/// matches.
+pub fn which(config: &Config, test: &str) -> Result<Option<String>> {
+    let manifest = Manifest::load(Path::new(config.output_dir()))?;
+    let matches: Vec<&ManifestFile> = manifest
+        .files()
+        .iter()
+        .filter(|x| {
+            is_match(&x.path, test) || x.source.as_deref().is_some_and(|x| is_match(x, test))
+        })
+        .collect();
+    if matches.is_empty() {
+        return Ok(None);
+    }
+
+    // The merged commits are only in the specs directory
+    let _cd = change_dir(config.specs_dir());
+    let mut report = String::new();
+    for file in matches {
+        report.push_str(&format!("{}\n", file.path));
+        report.push_str(&format!("    repo: {} at {}\n", file.repo, file.commit));
+        let source = match &file.source {
+            Some(source) => source,
+            None => {
+                report.push_str("    source: none\n");
+                continue;
+            }
+        };
+        report.push_str(&format!("    source: {}\n", source));
+
+        let parents = config
+            .find_repo(&file.repo)
+            .map(|x| x.parent.as_slice())
+            .unwrap_or_default();
+        for parent in parents {
+            // Every file of a repo is built from the same commit
+            let parent_commit = match manifest.files().iter().find(|x| &x.repo == parent) {
+                Some(x) => &x.commit,
+                None => {
+                    report.push_str(&format!("    {} has no tests to compare with\n", parent));
+                    continue;
+                }
+            };
+            let comparison = match (blob(&file.commit, source), blob(parent_commit, source)) {
+                (_, None) => format!("not in {} at {}", parent, parent_commit),
+                (Some(x), Some(y)) if x == y => format!("same as {} at {}", parent, parent_commit),
+                _ => format!("differs from {} at {}", parent, parent_commit),
+            };
+            report.push_str(&format!("    {}\n", comparison));
+        }
+    }
+    Ok(Some(report))
+}
+
+// Whether `path` is `test`, or ends with it as whole path components
+fn is_match(path: &str, test: &str) -> bool {
+    let test = test.trim_start_matches("./");
+    path == test || path.ends_with(&format!("/{}", test))
+}
+
+// The object id of a file at a commit, if the file exists
+fn blob(commit: &str, path: &str) -> Option<String> {
+    run(
+        "git",
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}:{}", commit, path),
+        ],
+    )
+    .ok()
+}
@@ -0,0 +1,991 @@
+// Runs the full pipeline against small local repos: a fake spec, and a fake
+// proposal forked from it with a trivial build script.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const TEST: &str = r#"(module (func (export "f") (result i32) (i32.const 0)))
+(assert_return (invoke "f") (i32.const 0))
+"#;
+
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    // A fixture with a spec repo, and a proposal forked from it that adds a
+    // test and a build script exiting with `build_status`
+    fn new(name: &str, build_status: u32) -> Fixture {
+        let dir = env::temp_dir().join(format!("wgt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let fixture = Fixture { dir };
+
+        fixture.git(".", &["init", "-q", "-b", "main", "spec"]);
+        fixture.commit("spec", &[("test/core/a.wast", TEST)], "Add a");
+        fixture.git(".", &["clone", "-q", "spec", "proposal"]);
+        fixture.commit(
+            "proposal",
+            &[
+                ("test/core/b.wast", TEST),
+                ("build.sh", &format!("exit {}\n", build_status)),
+            ],
+            "Add b",
+        );
+        // Move the spec on, so the proposal has something to merge
+        fixture.commit("spec", &[("test/core/c.wast", TEST)], "Add c");
+
+        fixture.write(
+            "config.toml",
+            &format!(
+                "[[repos]]\nname = \"spec\"\nurl = \"{}\"\n\n\
+                 [[repos]]\nname = \"proposal\"\nurl = \"{}\"\nparent = \"spec\"\n\
+                 pre_build = \"sh build.sh\"\n",
+                fixture.dir.join("spec").display(),
+                fixture.dir.join("proposal").display(),
+            ),
+        );
+        fixture
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    fn write(&self, path: &str, text: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    fn git(&self, dir: &str, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .current_dir(self.path(dir))
+            .args(args)
+            .env("GIT_AUTHOR_NAME", "test")
+            .env("GIT_AUTHOR_EMAIL", "test@localhost")
+            .env("GIT_COMMITTER_NAME", "test")
+            .env("GIT_COMMITTER_EMAIL", "test@localhost")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?}: {:?}", args, output);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    // Commit files to one of the fixture repos
+    fn commit(&self, repo: &str, files: &[(&str, &str)], message: &str) {
+        for (path, text) in files {
+            self.write(&format!("{}/{}", repo, path), text);
+        }
+        self.git(repo, &["add", "-A"]);
+        self.git(repo, &["commit", "-q", "-m", message]);
+    }
+
+    fn generate(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_wasm-generate-testsuite"))
+            .current_dir(&self.dir)
+            .args(args)
+            .env_remove("GITHUB_ACTIONS")
+            .env("SOURCE_DATE_EPOCH", "0")
+            .output()
+            .unwrap()
+    }
+
+    fn generate_ok(&self) {
+        let output = self.generate(&[]);
+        assert!(
+            output.status.success(),
+            "generate failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // The `merged` and `built` status of a repo in `VERSION.toml`
+    fn status(&self, name: &str) -> (String, bool) {
+        let version: toml::Value = toml::from_str(&self.read("tests/VERSION.toml")).unwrap();
+        let repo = find(&version, name);
+        (
+            repo["merged"].as_str().unwrap().to_owned(),
+            repo["built"].as_bool().unwrap(),
+        )
+    }
+
+    // The commit a repo is pinned to in `config-lock.toml`
+    fn locked(&self, name: &str) -> String {
+        let lock: toml::Value = toml::from_str(&self.read("config-lock.toml")).unwrap();
+        find(&lock, name)["commit"].as_str().unwrap().to_owned()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.path(path).exists()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn find<'a>(value: &'a toml::Value, name: &str) -> &'a toml::Value {
+    value["repos"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["name"].as_str() == Some(name))
+        .unwrap_or_else(|| panic!("{} is missing", name))
+}
+
+fn assert_locked(fixture: &Fixture, name: &str, commit: &str) {
+    let locked = fixture.locked(name);
+    assert!(
+        commit.starts_with(&locked),
+        "{} is locked to {}, not {}",
+        name,
+        locked,
+        commit
+    );
+}
+
+#[test]
+fn merges_and_copies_tests() {
+    let fixture = Fixture::new("merge", 0);
+    fixture.generate_ok();
+
+    assert_eq!(fixture.status("spec"), ("standalone".to_owned(), true));
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+    assert_locked(
+        &fixture,
+        "spec",
+        &fixture.git("spec", &["rev-parse", "HEAD"]),
+    );
+    assert_locked(
+        &fixture,
+        "proposal",
+        &fixture.git("proposal", &["rev-parse", "HEAD"]),
+    );
+
+    // The spec's tests are copied, and only the tests the proposal changed
+    assert!(fixture.exists("tests/wast/spec/a.wast"));
+    assert!(fixture.exists("tests/wast/spec/c.wast"));
+    assert!(fixture.exists("tests/js/spec/a.wast.js"));
+    assert!(fixture.exists("tests/js/spec/harness/harness.js"));
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+    assert!(fixture.exists("tests/js/proposal/b.wast.js"));
+    assert!(!fixture.exists("tests/wast/proposal/a.wast"));
+    assert!(!fixture.exists("tests/wast/proposal/c.wast"));
+    assert_eq!(fixture.read("tests/wast/proposal/b.wast"), TEST);
+
+    assert!(fixture.exists("tests/MANIFEST.toml"));
+    assert!(fixture.exists("tests/SHA256SUMS"));
+    assert!(!fixture.exists("tests.tmp"));
+    // Stamped with `SOURCE_DATE_EPOCH`
+    assert!(fixture
+        .read("tests/VERSION.toml")
+        .contains("generated = '1970-01-01T00:00:00Z'"));
+}
+
+#[test]
+fn broken_build() {
+    let fixture = Fixture::new("broken", 1);
+    fixture.generate_ok();
+
+    assert_eq!(fixture.status("spec"), ("standalone".to_owned(), true));
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), false));
+
+    // The scripts are still copied, but there are no generated tests
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+    assert!(!fixture.exists("tests/js/proposal"));
+    assert!(fixture.read("reports/summary.md").contains("| broken |"));
+}
+
+#[test]
+fn conflicted_merge() {
+    let fixture = Fixture::new("conflict", 0);
+    fixture.commit("spec", &[("test/core/a.wast", "(module)\n")], "Change a");
+    fixture.commit(
+        "proposal",
+        &[("test/core/a.wast", "(module (memory 1))\n")],
+        "Change a differently",
+    );
+    fixture.generate_ok();
+
+    assert_eq!(fixture.status("spec"), ("standalone".to_owned(), true));
+    assert_eq!(fixture.status("proposal"), ("conflicted".to_owned(), true));
+    assert!(fixture
+        .read("reports/proposal-conflicts.md")
+        .contains("test/core/a.wast"));
+}
+
+#[test]
+fn locked_commits_are_kept() {
+    let fixture = Fixture::new("lock", 0);
+    fixture.generate_ok();
+    let spec = fixture.git("spec", &["rev-parse", "HEAD"]);
+
+    // New upstream commits aren't picked up while the spec is locked
+    fixture.commit("spec", &[("test/core/d.wast", TEST)], "Add d");
+    fixture.generate_ok();
+    assert_locked(&fixture, "spec", &spec);
+    assert!(fixture.exists("tests/wast/spec/c.wast"));
+    assert!(!fixture.exists("tests/wast/spec/d.wast"));
+
+    // Until the lock is removed
+    fs::remove_file(fixture.path("config-lock.toml")).unwrap();
+    fixture.generate_ok();
+    assert_locked(
+        &fixture,
+        "spec",
+        &fixture.git("spec", &["rev-parse", "HEAD"]),
+    );
+    assert!(fixture.exists("tests/wast/spec/d.wast"));
+}
+
+#[test]
+fn resume_failed_run() {
+    let fixture = Fixture::new("resume", 0);
+
+    // Fail to fetch the proposal
+    fs::rename(fixture.path("proposal"), fixture.path("moved")).unwrap();
+    assert!(!fixture.generate(&[]).status.success());
+    assert!(fixture.exists("tests.tmp/wast/spec/a.wast"));
+    assert!(!fixture.exists("tests"));
+
+    // Only the proposal is built again, so the staged spec tests are kept
+    fixture.write("tests.tmp/wast/spec/a.wast", "(module)\n");
+    fs::rename(fixture.path("moved"), fixture.path("proposal")).unwrap();
+    let output = fixture.generate(&["--resume"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(fixture.read("tests/wast/spec/a.wast"), "(module)\n");
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+    assert_eq!(fixture.status("spec"), ("standalone".to_owned(), true));
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+    assert!(!fixture.exists("specs/.git/wgt-run.toml"));
+}
+
+#[test]
+fn filter_by_feature() {
+    let fixture = Fixture::new("features", 0);
+    fixture.commit(
+        "proposal",
+        &[("test/core/atomic.wast", "(module (memory 1 1 shared))\n")],
+        "Add atomic",
+    );
+    let output = fixture.generate(&["--exclude-features", "threads"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+    assert!(!fixture.exists("tests/wast/proposal/atomic.wast"));
+    assert!(!fixture.exists("tests/js/proposal/atomic.wast.js"));
+
+    // The converted tests are filtered by their script, without the copies
+    let output = fixture.generate(&["--only", "js", "--exclude-features", "threads"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!fixture.exists("tests/wast"));
+    assert!(fixture.exists("tests/js/proposal/b.wast.js"));
+    assert!(!fixture.exists("tests/js/proposal/atomic.wast.js"));
+}
+
+#[test]
+fn feature_tags_without_wast() {
+    let fixture = Fixture::new("feature-tags", 0);
+    fixture.commit(
+        "proposal",
+        &[("test/core/atomic.wast", "(module (memory 1 1 shared))\n")],
+        "Add atomic",
+    );
+    let config = fixture.read("config.toml");
+    fixture.write("config.toml", &format!("feature_tags = true\n\n{}", config));
+    let output = fixture.generate(&["--only", "js"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let tags = fixture.read("tests/FEATURES.toml");
+    let atomic = tags
+        .lines()
+        .find(|x| x.contains("js/proposal/atomic.wast.js"))
+        .unwrap_or_else(|| panic!("{}", tags));
+    assert!(atomic.contains("threads"), "{}", tags);
+    assert!(!tags.contains("wast/proposal/atomic.wast\""), "{}", tags);
+}
+
+#[test]
+fn list_tests() {
+    let fixture = Fixture::new("list", 0);
+    let output = fixture.generate(&["list-tests"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let tests: Vec<&str> = stdout.lines().collect();
+    assert!(tests.contains(&"wast/spec/a.wast"));
+    assert!(tests.contains(&"wast/spec/c.wast"));
+    assert!(tests.contains(&"js/spec/harness/harness.js"));
+    assert!(tests.contains(&"js/proposal/b.wast.js"));
+    assert!(!tests.contains(&"wast/proposal/a.wast"));
+    assert!(!fixture.exists("tests"));
+}
+
+#[test]
+fn which_test() {
+    let fixture = Fixture::new("which", 0);
+    fixture.commit(
+        "proposal",
+        &[("test/core/a.wast", "(module)\n")],
+        "Change a",
+    );
+    fixture.generate_ok();
+
+    let output = fixture.generate(&["which", "b.wast"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("wast/proposal/b.wast\n"));
+    assert!(stdout.contains("js/proposal/b.wast.js\n"));
+    assert!(stdout.contains("not in spec"));
+
+    let output = fixture.generate(&["which", "wast/spec/a.wast"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("repo: spec"));
+    assert!(!stdout.contains("proposal"));
+
+    let output = fixture.generate(&["which", "test/core/a.wast"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("differs from spec"));
+
+    assert!(!fixture
+        .generate(&["which", "missing.wast"])
+        .status
+        .success());
+}
+
+#[test]
+fn removed_tests_are_reported() {
+    let fixture = Fixture::new("removed", 0);
+    fixture.generate_ok();
+
+    fixture.git("spec", &["rm", "-q", "test/core/c.wast"]);
+    fixture.git("spec", &["mv", "test/core/a.wast", "test/core/d.wast"]);
+    fixture.git("spec", &["commit", "-q", "-m", "Remove c, rename a"]);
+    fs::remove_file(fixture.path("config-lock.toml")).unwrap();
+    fixture.generate_ok();
+
+    let summary = fixture.read("reports/summary.md");
+    assert!(summary.contains("- spec: `test/core/c.wast` removed"));
+    assert!(summary.contains("- spec: `test/core/a.wast` renamed to `test/core/d.wast`"));
+    let json = fixture.read("reports/summary.json");
+    assert!(json.contains(r#""removed":["test/core/c.wast"]"#));
+    assert!(json.contains(r#""renamed":[{"from":"test/core/a.wast","to":"test/core/d.wast"}]"#));
+}
+
+#[test]
+fn changes_are_reported() {
+    let fixture = Fixture::new("changes", 0);
+    fixture.generate_ok();
+    assert!(!fixture.exists("tests.prev"));
+    assert!(!fixture
+        .read("reports/summary.md")
+        .contains("## Changed tests"));
+    assert!(fixture
+        .read("reports/summary.json")
+        .contains(r#""changes":null"#));
+
+    fixture.git("spec", &["rm", "-q", "test/core/c.wast"]);
+    fixture.commit(
+        "spec",
+        &[
+            ("test/core/a.wast", "(module)\n"),
+            ("test/core/d.wast", TEST),
+        ],
+        "Remove c, change a, add d",
+    );
+    fs::remove_file(fixture.path("config-lock.toml")).unwrap();
+    fixture.generate_ok();
+
+    // The previous tests are kept as they were
+    assert_eq!(fixture.read("tests.prev/wast/spec/a.wast"), TEST);
+    assert!(fixture.exists("tests.prev/wast/spec/c.wast"));
+    assert!(!fixture.exists("tests.prev/wast/spec/d.wast"));
+
+    let summary = fixture.read("reports/summary.md");
+    let changes = &summary[summary.find("## Changed tests").unwrap()..];
+    let spec = &changes[changes.find("### spec").unwrap()..];
+    assert!(spec.contains("Added:\n- wast/d.wast\n"));
+    assert!(spec.contains("Removed:\n- wast/c.wast\n"));
+    let modified = &spec[spec.find("Modified:").unwrap()..];
+    assert!(modified.contains("- wast/a.wast\n"));
+    // The README written for each repo isn't a test
+    assert!(!changes.contains("README.md"));
+    let json = fixture.read("reports/summary.json");
+    assert!(json.contains(r#""changes":[{"name":"#));
+    assert!(json.contains(r#""added":["wast/d.wast","js/d.wast.js"]"#));
+
+    // Nothing changes when generating again
+    fixture.generate_ok();
+    assert!(fixture
+        .read("reports/summary.md")
+        .contains("## Changed tests\n\nNo changes.\n"));
+    assert!(fixture.exists("tests.prev/wast/spec/d.wast"));
+}
+
+#[test]
+fn licenses() {
+    let fixture = Fixture::new("licenses", 0);
+    fixture.commit("spec", &[("LICENSE", "Spec license\n")], "Add a license");
+    fixture.generate_ok();
+
+    assert_eq!(fixture.read("tests/wast/spec/LICENSE"), "Spec license\n");
+    assert_eq!(fixture.read("tests/js/spec/LICENSE"), "Spec license\n");
+    assert!(!fixture.exists("tests/wast/proposal/LICENSE"));
+    let attribution = fixture.read("tests/ATTRIBUTION.md");
+    let spec = attribution
+        .lines()
+        .find(|x| x.starts_with("| spec |"))
+        .unwrap();
+    assert!(spec.contains(&fixture.dir.join("spec").display().to_string()));
+    assert!(spec.ends_with(" | LICENSE |"));
+    let proposal = attribution
+        .lines()
+        .find(|x| x.starts_with("| proposal |"))
+        .unwrap();
+    assert!(proposal.ends_with(" | none |"));
+}
+
+#[test]
+fn dedup_with_two_shared_versions() {
+    let fixture = Fixture::new("dedup", 0);
+    let mut config = String::from("dedup_dir = \"shared\"\n");
+    for (name, test) in &[("a", 1), ("b", 1), ("c", 2), ("d", 2), ("e", 3)] {
+        fixture.git(".", &["init", "-q", "-b", "main", name]);
+        let text = format!("(module (func (result i32) (i32.const {})))\n", test);
+        fixture.commit(name, &[("test/core/t.wast", &text)], "Add t");
+        fixture.commit(name, &[("test/core/u.wast", TEST)], "Add u");
+        config.push_str(&format!(
+            "\n[[repos]]\nname = \"{}\"\nurl = \"{}\"\n",
+            name,
+            fixture.dir.join(name).display()
+        ));
+    }
+    fixture.write("config.toml", &config);
+    fixture.generate_ok();
+
+    // `t.wast` has two shared versions, so each repo keeps its own
+    for (name, test) in &[("a", 1), ("b", 1), ("c", 2), ("d", 2)] {
+        let path = format!("tests/wast/{}/t.wast", name);
+        assert!(fixture
+            .read(&path)
+            .contains(&format!("(i32.const {})", test)));
+    }
+    assert!(!fixture.exists("tests/wast/shared/t.wast"));
+    // While `u.wast` is the same everywhere
+    assert_eq!(fixture.read("tests/wast/shared/u.wast"), TEST);
+    assert!(!fixture.exists("tests/wast/a/u.wast"));
+    assert!(fixture
+        .read("tests/MANIFEST.toml")
+        .contains("path = 'wast/shared/u.wast'"));
+}
+
+#[test]
+fn output_name() {
+    let fixture = Fixture::new("output-name", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &config.replace(
+            "name = \"proposal\"\n",
+            "name = \"proposal\"\noutput_name = \"prop\"\n",
+        ),
+    );
+    fixture.generate_ok();
+
+    assert!(fixture.exists("tests/wast/prop/b.wast"));
+    assert!(fixture.exists("tests/js/prop/b.wast.js"));
+    assert!(!fixture.exists("tests/wast/proposal"));
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+    let manifest: toml::Value = toml::from_str(&fixture.read("tests/MANIFEST.toml")).unwrap();
+    let file = manifest["file"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["path"].as_str() == Some("wast/prop/b.wast"))
+        .unwrap();
+    assert_eq!(file["repo"].as_str(), Some("proposal"));
+}
+
+#[test]
+fn layout() {
+    let fixture = Fixture::new("layout", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!(
+            "layout = \"{{repo}}/{{kind}}/{{path}}\"\n\n{}\n\
+             [[repos.variants]]\nname = \"stable\"\nparent = \"spec\"\n",
+            config
+        ),
+    );
+    fixture.generate_ok();
+
+    assert!(fixture.exists("tests/spec/wast/a.wast"));
+    assert!(fixture.exists("tests/spec/js/harness/harness.js"));
+    assert!(fixture.exists("tests/proposal/js/b.wast.js"));
+    assert!(fixture.exists("tests/stable/proposal/wast/b.wast"));
+    assert!(!fixture.exists("tests/wast"));
+    assert!(fixture
+        .read("tests/MANIFEST.toml")
+        .contains("spec/wast/a.wast"));
+
+    // Verifying compares the tests in the layout, including the variants'
+    let output = fixture.generate(&["--verify"]);
+    assert!(output.status.success(), "{:?}", output);
+    fixture.write("tests/stable/proposal/wast/b.wast", "(module)\n");
+    let output = fixture.generate(&["--verify"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("## stable/proposal\n"), "{}", stdout);
+    assert!(stdout.contains("- wast/b.wast\n"), "{}", stdout);
+    assert!(!stdout.contains("## proposal\n"), "{}", stdout);
+
+    fixture.write("tests/stable/proposal/wast/b.wast", TEST);
+    fixture.write("tests/spec/wast/a.wast", "(module)\n");
+    let output = fixture.generate(&["--verify"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("wast/a.wast"));
+}
+
+#[test]
+fn verify_leaves_published_outputs() {
+    let fixture = Fixture::new("verify-published", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!(
+            "archive = \"tests.tar.gz\"\nshell = \"exit 1\"\n\
+             expectations = \"expectations.toml\"\n\n{}",
+            config
+        ),
+    );
+    fixture.generate_ok();
+    assert!(fixture.read("expectations.toml").contains("fail"));
+    fixture.write("expectations.toml", "# edited by hand\n");
+    let archive = fs::read(fixture.path("tests.tar.gz")).unwrap();
+    let modified = |path| {
+        fs::metadata(fixture.path(path))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let archived = modified("tests.tar.gz");
+
+    let output = fixture.generate(&["--verify"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        fs::read(fixture.path("tests.tar.gz")).unwrap() == archive,
+        "the archive changed"
+    );
+    assert_eq!(modified("tests.tar.gz"), archived);
+    assert_eq!(fixture.read("expectations.toml"), "# edited by hand\n");
+    assert!(!fixture.exists("reports.verify/results.toml"));
+}
+
+#[test]
+fn wpt_layout() {
+    let fixture = Fixture::new("wpt", 0);
+    fixture.commit(
+        "spec",
+        &[("test/js-api/a.any.js", "test(() => {});\n")],
+        "Add a js-api test",
+    );
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!(
+            "layout = \"wpt\"\ndirectives = [\"wasm.enabled:true\", \"wasm.{{repo}}:true\"]\n\n{}",
+            config
+        ),
+    );
+    fixture.generate_ok();
+
+    assert!(fixture.exists("tests/wasm/core/spec/a.wast.js"));
+    assert!(fixture.exists("tests/wasm/resources/spec/harness.js"));
+    assert!(fixture.exists("tests/wasm/jsapi/spec/a.any.js"));
+    assert!(fixture.exists("tests/wast/spec/a.wast"));
+    assert!(!fixture.exists("tests/js"));
+
+    // The directives are also written as the prefs of each directory
+    let metadata = "prefs: [wasm.enabled:true, wasm.spec:true]\n";
+    assert_eq!(fixture.read("tests/wasm/core/spec/__dir__.ini"), metadata);
+    assert_eq!(fixture.read("tests/wasm/jsapi/spec/__dir__.ini"), metadata);
+    assert!(fixture.exists("tests/wasm/core/proposal/__dir__.ini"));
+
+    let output = fixture.generate(&["--verify"]);
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[test]
+fn excluded_content_in_subdirectory() {
+    let fixture = Fixture::new("excluded-content", 0);
+    fixture.commit(
+        "spec",
+        &[
+            ("test/core/sub/e.wast", "(module) ;; unsupported\n"),
+            ("test/core/sub/f.wast", TEST),
+        ],
+        "Add e and f",
+    );
+    let config = fixture.read("config.toml");
+    let excluded = "excluded_content = [\"unsupported\"]\n";
+    fixture.write("config.toml", &format!("{}\n{}", excluded, config));
+    fixture.generate_ok();
+
+    // The generated tests of the excluded script are flattened, and excluded
+    // all the same
+    assert!(!fixture.exists("tests/wast/spec/sub/e.wast"));
+    assert!(!fixture.exists("tests/js/spec/e.wast.js"));
+    assert!(fixture.exists("tests/wast/spec/sub/f.wast"));
+    assert!(fixture.exists("tests/js/spec/f.wast.js"));
+
+    // Including once renamed to `.any.js`
+    fixture.write(
+        "config.toml",
+        &format!("layout = \"wpt\"\nany_js = true\n{}\n{}", excluded, config),
+    );
+    fixture.generate_ok();
+    assert!(!fixture.exists("tests/wasm/core/spec/e.wast.any.js"));
+    assert!(fixture.exists("tests/wasm/core/spec/f.wast.any.js"));
+}
+
+#[test]
+fn wpt_any_js() {
+    let fixture = Fixture::new("wpt-any-js", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!(
+            "layout = \"wpt\"\nany_js = true\nlong_timeout_tests = [\"^a\\\\.\"]\n\n{}",
+            config
+        ),
+    );
+    fixture.generate_ok();
+
+    // The harness is loaded by the test, after any other `META` comments
+    let script = fixture.read("tests/wasm/core/spec/a.wast.any.js");
+    assert!(
+        script.starts_with(
+            "// META: timeout=long\n\
+             // META: global=window,dedicatedworker\n\
+             // META: script=/wasm/resources/spec/harness.js\n"
+        ),
+        "{}",
+        script
+    );
+    assert!(fixture.exists("tests/wasm/core/proposal/b.wast.any.js"));
+    assert!(!fixture.exists("tests/wasm/core/spec/a.wast.js"));
+    assert!(fixture.exists("tests/wasm/resources/spec/harness.js"));
+
+    let manifest = fixture.read("tests/MANIFEST.toml");
+    assert!(manifest.contains("wasm/core/spec/a.wast.any.js"));
+    let output = fixture.generate(&["list-tests"]);
+    let listed = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        listed.contains("wasm/core/spec/a.wast.any.js\n"),
+        "{}",
+        listed
+    );
+}
+
+#[test]
+fn only_some_kinds() {
+    let fixture = Fixture::new("only", 0);
+    let output = fixture.generate(&["--only", "js"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(fixture.exists("tests/js/spec/a.wast.js"));
+    assert!(fixture.exists("tests/js/proposal/b.wast.js"));
+    assert!(!fixture.exists("tests/wast"));
+
+    assert!(!fixture.generate(&["--only", "html"]).status.success());
+}
+
+#[test]
+fn interrupted_run() {
+    let fixture = Fixture::new("interrupt", 0);
+    let config = fixture.read("config.toml");
+    // The signal reaches the build too, which fails because of it
+    fixture.write(
+        "config.toml",
+        &config.replace("sh build.sh", "kill -INT $PPID; exit 1"),
+    );
+
+    // The run stops while the proposal is built, keeping the staged tests of
+    // the spec and not recording the broken build
+    let output = fixture.generate(&[]);
+    assert_eq!(output.status.code(), Some(130), "{:?}", output);
+    assert!(fixture.exists("tests.tmp/wast/spec/a.wast"));
+    assert!(!fixture.exists("tests"));
+    assert!(!fixture.exists("specs/.git/MERGE_HEAD"));
+    assert!(!fixture.read("specs/.git/wgt-run.toml").contains("proposal"));
+
+    // And resumes by building the proposal again
+    fixture.write("config.toml", &config);
+    let output = fixture.generate(&["--resume"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+}
+
+#[test]
+fn interrupted_post_processing() {
+    let fixture = Fixture::new("interrupt-post", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!("post_process = \"kill -INT $PPID\"\n{}", config),
+    );
+
+    // Nothing after the hook runs, and the previous tests are kept
+    let output = fixture.generate(&[]);
+    assert_eq!(output.status.code(), Some(130), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("keeping the previous tests"));
+    assert!(!fixture.exists("tests"));
+    assert!(!fixture.exists("tests.tmp/SHA256SUMS"));
+    assert!(!fixture.exists("config-lock.toml"));
+}
+
+#[test]
+fn concurrent_runs() {
+    let fixture = Fixture::new("concurrent", 0);
+    // Run another command against the specs directory while building
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &config.replace(
+            "sh build.sh",
+            &format!(
+                "cd .. && {} status > status.out 2>&1; echo $? > status.code",
+                env!("CARGO_BIN_EXE_wasm-generate-testsuite")
+            ),
+        ),
+    );
+
+    fixture.generate_ok();
+    assert_eq!(fixture.read("status.code").trim(), "1");
+    assert!(fixture
+        .read("status.out")
+        .contains("another run is using specs, pass --wait"));
+}
+
+#[test]
+fn build_script() {
+    let fixture = Fixture::new("build-script", 0);
+    fixture.commit(
+        "proposal",
+        &[(
+            "gen.sh",
+            "[ \"$1\" = --js ] && echo generated > \"$2/b.wast.js\"\n",
+        )],
+        "Add a generator",
+    );
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!("{}build_script = \"gen.sh\"\npython = \"sh\"\n", config),
+    );
+
+    fixture.generate_ok();
+    assert_eq!(fixture.read("tests/js/proposal/b.wast.js"), "generated\n");
+    assert!(!fixture.exists("tests/js/proposal/harness/harness.js"));
+}
+
+#[test]
+fn interpreter_build() {
+    let fixture = Fixture::new("interpreter", 0);
+    fixture.commit(
+        "proposal",
+        &[(
+            "interpreter/build.sh",
+            "echo built >> ../builds\necho interpreter > interpreter/wasm\n",
+        )],
+        "Add an interpreter",
+    );
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!(
+            "{}interpreter_build = \"sh interpreter/build.sh\"\n\
+             interpreter_outputs = [\"interpreter/wasm\"]\n",
+            config
+        ),
+    );
+
+    // The interpreter is only built again once its source changes
+    fixture.generate_ok();
+    fixture.generate_ok();
+    assert_eq!(fixture.read("builds"), "built\n");
+    assert_eq!(fixture.read("specs/interpreter/wasm"), "interpreter\n");
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+
+    // A failing interpreter build is told apart from failing to build the tests
+    fixture.write(
+        "config.toml",
+        &format!("{}interpreter_build = \"exit 1\"\n", config),
+    );
+    fixture.generate_ok();
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), false));
+    assert!(fixture
+        .read("reports/summary.md")
+        .contains("| broken interpreter |"));
+}
+
+#[test]
+fn fallback_generator() {
+    let fixture = Fixture::new("fallback-generator", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!("{}build_script = \"missing.sh\"\npython = \"sh\"\n", config),
+    );
+
+    // The scripts are converted instead, and flagged as such
+    fixture.generate_ok();
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+    assert!(fixture.exists("tests/js/proposal/b.wast.js"));
+    let manifest: toml::Value = toml::from_str(&fixture.read("tests/MANIFEST.toml")).unwrap();
+    let fallback = |path: &str| {
+        let files = manifest["file"].as_array().unwrap();
+        let file = files.iter().find(|x| x["path"].as_str() == Some(path));
+        file.unwrap().get("fallback").and_then(|x| x.as_bool())
+    };
+    assert_eq!(fallback("js/proposal/b.wast.js"), Some(true));
+    assert_eq!(fallback("wast/proposal/b.wast"), None);
+    assert_eq!(fallback("js/spec/c.wast.js"), None);
+}
+
+#[test]
+fn long_timeout_tests() {
+    let fixture = Fixture::new("long-timeout", 0);
+    let config = fixture.read("config.toml");
+    fixture.write(
+        "config.toml",
+        &format!("long_timeout_tests = [\"^b\\\\.\"]\n\n{}", config),
+    );
+    fixture.generate_ok();
+
+    let script = fixture.read("tests/js/proposal/b.wast.js");
+    assert!(script.starts_with("// META: timeout=long\n"), "{}", script);
+    assert!(!fixture
+        .read("tests/js/spec/c.wast.js")
+        .contains("META: timeout=long"));
+}
+
+#[test]
+fn config_dir() {
+    let fixture = Fixture::new("config-dir", 0);
+    let config = fixture.read("config.toml");
+    let (spec, proposal) = config.split_at(config.rfind("[[repos]]").unwrap());
+    fs::remove_file(fixture.path("config.toml")).unwrap();
+    fixture.write("config.d/10-spec.toml", spec);
+    fixture.write("config.d/20-proposal.toml", proposal);
+    fixture.write("config.d/README.md", "not a config");
+
+    fixture.generate_ok();
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+
+    // Entries may only be defined once across the files
+    fixture.write("config.toml", &format!("jobs = 2\n\n{}", proposal));
+    let output = fixture.generate(&["config", "check"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(
+            "repo `proposal` is defined in both config.toml and config.d/20-proposal.toml"
+        ),
+        "{}",
+        stdout
+    );
+    fixture.write("config.toml", "jobs = 2\n");
+    fixture.write("config.d/30-jobs.toml", "jobs = 4\n");
+    let output = fixture.generate(&["config", "check"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("`jobs` is set in both config.toml and config.d/30-jobs.toml"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn unreachable_pinned_commit() {
+    let fixture = Fixture::new("unreachable", 0);
+    fixture.generate_ok();
+    let spec = fixture.locked("spec");
+    let lock = fixture.read("config-lock.toml");
+    let missing = "0123456789abcdef0123456789abcdef01234567";
+    fixture.write("config-lock.toml", &lock.replace(&spec, missing));
+
+    let output = fixture.generate(&["status"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "pinned commit {} is no longer reachable from",
+            missing
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn shallow_fetches_in_parallel() {
+    let fixture = Fixture::new("shallow", 0);
+    for repo in &["spec", "proposal"] {
+        fixture.commit(repo, &[("test/core/e.wast", TEST)], "Add e");
+    }
+    // Fetch both repos shallowly and standalone, as their merge base would be
+    // cut off
+    let config = fixture
+        .read("config.toml")
+        .replace("parent = \"spec\"\n", "")
+        .replace("url = \"", "fetch_depth = 1\nurl = \"file://");
+    fixture.write("config.toml", &config);
+
+    let output = fixture.generate(&["--net-jobs", "2"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fixture.git("specs", &["rev-parse", "--is-shallow-repository"]),
+        "true"
+    );
+    assert!(fixture.exists("tests/wast/spec/e.wast"));
+    assert!(fixture.exists("tests/wast/proposal/b.wast"));
+    assert!(!fixture.exists("specs/.git/FETCH_HEAD"));
+}
+
+#[test]
+fn net_jobs() {
+    let fixture = Fixture::new("net-jobs", 0);
+    let output = fixture.generate(&["--net-jobs=0"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("invalid value for --net-jobs: `0`"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("WGT_NET_JOBS"), "{}", stderr);
+
+    let output = fixture.generate(&["--net-jobs", "1"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(fixture.status("proposal"), ("merged".to_owned(), true));
+}
+
+#[test]
+fn symlinks_are_rejected() {
+    let fixture = Fixture::new("symlinks", 0);
+    let output = fixture.generate(&["--link-mode=symlink"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("use `hardlink` instead"));
+}
@@ -68,6 +68,19 @@ pub fn convert<P: AsRef<Path>>(path: P, wast: &str) -> Result<String> {
     Ok(format_js(Path::new(filename), &out))
 }
 
+pub fn validate<P: AsRef<Path>>(path: P, wast: &str) -> Result<()> {
+    let filename = path.as_ref();
+    let adjust_wast = |mut err: wast::Error| {
+        err.set_path(filename);
+        err.set_text(wast);
+        err
+    };
+
+    let buf = wast::parser::ParseBuffer::new(wast).map_err(adjust_wast)?;
+    wast::parser::parse::<wast::Wast>(&buf).map_err(adjust_wast)?;
+    Ok(())
+}
+
 fn format_js(filename: &Path, text: &str) -> String {
     let config = dprint_plugin_typescript::configuration::ConfigurationBuilder::new()
         .deno()